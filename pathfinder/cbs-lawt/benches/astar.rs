@@ -0,0 +1,199 @@
+// cargo bench --bench astar
+// Plain timing loop, no harness, on maps shaped like `test_case` in main.rs.
+use cbs_lawt::astar::AStar;
+use cbs_lawt::cbs::solve_mapf;
+use cbs_lawt::dstar::DStarLite;
+use cbs_lawt::grid::Grid;
+use cbs_lawt::heuristic::HeuristicCache;
+use cbs_lawt::hpa::Hpa;
+use cbs_lawt::jps::Jps;
+use cbs_lawt::landmark::Landmarks;
+use cbs_lawt::prelude::{CellInfo, Constraint, Pair};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn formation(size: Pair, spread: usize, offset: Pair) -> Vec<Pair> {
+    let mut out = Vec::with_capacity(size.0 * size.1);
+    for x in 0..size.0 {
+        for y in 0..size.1 {
+            out.push(Pair(x * spread + offset.0, y * spread + offset.1));
+        }
+    }
+    out
+}
+
+// Seeded, so every run benches the same map
+fn make_grid(extent: Pair, density: f64, avoid: &[Pair], seed: u64) -> Grid<CellInfo> {
+    let mut grid: Grid<CellInfo> = Grid::init(
+        extent,
+        CellInfo {
+            cost: 1,
+            blocked: false,
+        },
+    );
+    let mut rng = StdRng::seed_from_u64(seed);
+    for i in 0..=extent.0 {
+        for j in 0..=extent.1 {
+            let roll: f64 = rng.gen();
+            if roll < density && !avoid.contains(&Pair(i, j)) {
+                grid[Pair(i, j)].blocked = true
+            }
+        }
+    }
+    grid
+}
+
+fn test_case(seed: u64, destination_offset: Pair) -> AStar {
+    let (origins, destinations, grid) = test_grid(seed, destination_offset);
+    AStar::init(origins, destinations, Pair(0, 0), grid)
+}
+
+fn test_grid(seed: u64, destination_offset: Pair) -> (Vec<Pair>, Vec<Pair>, Grid<CellInfo>) {
+    let origins = formation(Pair(1, 5), 2, Pair(3, 2));
+    let destinations = formation(Pair(1, 5), 2, destination_offset);
+    let mut clear = origins.clone();
+    clear.extend(destinations.iter().copied());
+    let grid = make_grid(Pair(100, 75), 0.10, &clear, seed);
+    (origins, destinations, grid)
+}
+
+fn bench<T>(name: &str, iterations: u32, mut f: impl FnMut() -> T) {
+    let mut total = Duration::ZERO;
+    let mut fastest = Duration::MAX;
+    for _ in 0..iterations {
+        let start = Instant::now();
+        black_box(f());
+        let elapsed = start.elapsed();
+        total += elapsed;
+        fastest = fastest.min(elapsed);
+    }
+    println!(
+        "{:<40} mean {:>12?}  min {:>12?}  ({} runs)",
+        name,
+        total / iterations,
+        fastest,
+        iterations
+    );
+}
+
+// Forbid each unit from the cells of its own unconstrained path, slightly after it would be there,
+// so the search has to wait or detour.
+fn path_constraints(astar: &AStar) -> Vec<Constraint> {
+    let mut constraints = Vec::new();
+    for origin in &astar.origins {
+        let path = astar.astar(*origin, &[]).expect("Unreachable destination!");
+        for scored_cell in path.iter().skip(1).step_by(3) {
+            constraints.push(Constraint::Avoid {
+                uid: *origin,
+                location: scored_cell.location,
+                duration: Pair(scored_cell.duration.0, scored_cell.duration.1 + 2),
+            });
+        }
+    }
+    constraints
+}
+
+fn main() {
+    let near = test_case(1, Pair(15, 3));
+    let far = test_case(2, Pair(90, 60));
+
+    bench("init 100x75 (5 destinations)", 20, || {
+        test_case(1, Pair(15, 3))
+    });
+    // The grid does not change between refreshes, so every field comes from the cache
+    let cache = HeuristicCache::new();
+    let (origins, destinations, grid) = test_grid(1, Pair(15, 3));
+    let mut warm = AStar::init_cached(origins, destinations, Pair(0, 0), grid, &cache);
+    bench("init 100x75, cached heuristics", 20, || {
+        warm.refresh_heuristic(&cache);
+    });
+    for (name, astar) in [("near", &near), ("far", &far)] {
+        bench(&format!("astar unconstrained, {}", name), 50, || {
+            for origin in &astar.origins {
+                black_box(astar.astar(*origin, &[]));
+            }
+        });
+        let constraints = path_constraints(astar);
+        bench(&format!("astar constrained, {}", name), 50, || {
+            for origin in &astar.origins {
+                black_box(astar.astar(*origin, &constraints));
+            }
+        });
+    }
+    let (origins, destinations, grid) = test_grid(2, Pair(90, 60));
+    bench("landmarks 100x75 (8 landmarks)", 10, || {
+        Landmarks::new(&grid, Pair(0, 0), 8)
+    });
+    let landmarks = Arc::new(Landmarks::new(&grid, Pair(0, 0), 8));
+    // What landmarks are for: a new destination each search. The exact heuristic then pays a
+    // Djikstra per retarget, landmarks only a pass over the targets.
+    let targets: Vec<Pair> = (0..20)
+        .map(|i| Pair(60 + i, 40 + (i * 7) % 30))
+        .filter(|target| !grid[*target].blocked)
+        .collect();
+    let mut far_exact = test_case(2, Pair(90, 60));
+    let mut far_alt = AStar::init_with_landmarks(
+        origins.clone(),
+        destinations,
+        Pair(0, 0),
+        test_grid(2, Pair(90, 60)).2,
+        landmarks,
+    );
+    bench("astar unconstrained, far, landmarks", 50, || {
+        for origin in &far_alt.origins {
+            black_box(far_alt.astar(*origin, &[]));
+        }
+    });
+    for (name, astar) in [("exact", &mut far_exact), ("landmarks", &mut far_alt)] {
+        let mut idx = 0;
+        bench(
+            &format!("astar retargeted each search, {}", name),
+            50,
+            || {
+                idx = (idx + 1) % targets.len();
+                astar.destinations = vec![targets[idx]];
+                astar.refresh_heuristic(&HeuristicCache::new());
+                black_box(astar.astar(origins[0], &[]))
+            },
+        );
+    }
+    bench("solve_mapf, near", 10, || solve_mapf(&near));
+
+    let big = make_grid(Pair(399, 299), 0.1, &[Pair(0, 0), Pair(399, 299)], 3);
+    bench("hpa 400x300 build (cluster 16)", 5, || {
+        Hpa::new(make_grid(Pair(399, 299), 0.1, &[], 3), Pair(0, 0), 16)
+    });
+    bench("reachable 400x300, budget 40", 50, || {
+        big.reachable(Pair(200, 150).extend(Pair(0, 0)), 40, &[])
+    });
+    let mut dstar_grid = make_grid(Pair(399, 299), 0.1, &[Pair(0, 0), Pair(399, 299)], 3);
+    bench("dstar 400x300 plan, corner to corner", 5, || {
+        DStarLite::new(&dstar_grid, Pair(0, 0), Pair(0, 0), Pair(399, 299)).route(&dstar_grid)
+    });
+    let mut dstar = DStarLite::new(&dstar_grid, Pair(0, 0), Pair(0, 0), Pair(399, 299));
+    dstar.route(&dstar_grid);
+    let mut blocked = false;
+    bench("dstar 400x300 replan after 3x3 change", 20, || {
+        let changed = Pair(200, 150).extend(Pair(2, 2));
+        blocked = !blocked;
+        dstar_grid.set_blocked(changed, blocked);
+        dstar.update(&dstar_grid, &[changed]);
+        dstar.route(&dstar_grid)
+    });
+    let jps = Jps::new(&big, Pair(0, 0));
+    bench("jps 400x300 route, corner to corner", 20, || {
+        jps.route(Pair(0, 0), Pair(399, 299))
+    });
+    let mut hpa = Hpa::new(big, Pair(0, 0), 16);
+    bench("hpa 400x300 route, corner to corner", 50, || {
+        hpa.route(Pair(0, 0), Pair(399, 299))
+    });
+    let mut blocked = false;
+    bench("hpa 400x300 set_blocked 3x3", 50, || {
+        blocked = !blocked;
+        hpa.set_blocked(Pair(200, 150).extend(Pair(2, 2)), blocked)
+    });
+}
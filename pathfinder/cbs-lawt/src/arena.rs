@@ -0,0 +1,62 @@
+// Index-based node store for searches. Nodes point back at their predecessor by index, so a
+// search allocates one Vec instead of one Rc per node, and paths are rebuilt by walking indices.
+
+pub type NodeId = usize;
+
+struct Node<T> {
+    item: T,
+    prev: Option<NodeId>,
+}
+
+pub struct Arena<T> {
+    nodes: Vec<Node<T>>,
+}
+
+impl<T> Arena<T> {
+    pub fn with_capacity(capacity: usize) -> Arena<T> {
+        Arena {
+            nodes: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, item: T, prev: Option<NodeId>) -> NodeId {
+        self.nodes.push(Node { item, prev });
+        self.nodes.len() - 1
+    }
+
+    pub fn get(&self, id: NodeId) -> &T {
+        &self.nodes[id].item
+    }
+
+    pub fn prev(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes[id].prev
+    }
+
+    // Walks from `id` back to the root of its chain, `id` first
+    pub fn chain(&self, id: NodeId) -> impl Iterator<Item = &T> {
+        let mut next = Some(id);
+        std::iter::from_fn(move || {
+            let id = next?;
+            next = self.prev(id);
+            Some(self.get(id))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chains_walk_back_to_their_root() {
+        let mut arena = Arena::with_capacity(4);
+        let root = arena.push('a', None);
+        let left = arena.push('b', Some(root));
+        let right = arena.push('c', Some(root));
+        let leaf = arena.push('d', Some(left));
+        assert_eq!(arena.chain(leaf).collect::<String>(), "dba");
+        assert_eq!(arena.chain(right).collect::<String>(), "ca");
+        assert_eq!(arena.prev(root), None);
+        assert_eq!(*arena.get(right), 'c');
+    }
+}
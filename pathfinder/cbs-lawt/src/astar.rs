@@ -1,72 +1,142 @@
+use crate::arena::{Arena, NodeId};
+use crate::constraints::UnitConstraints;
+use crate::formation::FormationKeeping;
+use crate::goal::Goal;
 use crate::grid::Grid;
+use crate::heuristic::{Heuristic, HeuristicCache};
+use crate::landmark::Landmarks;
+use crate::objective::Objective;
 use crate::prelude::*;
+use crate::zoc::{Zoc, ZocRule};
 use std::cmp::min;
-use std::collections::{BinaryHeap, HashMap};
-use std::rc::Rc;
-use std::usize;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::Arc;
 
-fn filter_constraints(uid: Pair, constraints: &[Constraint]) -> Vec<Constraint> {
-    let mut out = Vec::with_capacity(constraints.len());
-    for constraint in constraints {
-        if constraint.uid == uid {
-            out.push(*constraint);
+// Search states are (origin, arrival, departure, waypoints reached). Once both times are past the
+// horizon no constraint can tell them apart, so they collapse into one state and the earliest one
+// dominates.
+type StateKey = (Pair, Pair, usize);
+
+fn state_key(scored_cell: &ScoredCell, horizon: usize) -> StateKey {
+    let duration = Pair(
+        min(scored_cell.duration.0, horizon + 1),
+        min(scored_cell.duration.1, horizon + 1),
+    );
+    (scored_cell.location.origin, duration, scored_cell.leg)
+}
+
+fn reconstruct_path(arena: &Arena<ScoredCell>, last: NodeId) -> Path {
+    let mut path: Path = Vec::with_capacity(arena.get(last).duration.1 + 1);
+    for scored_cell in arena.chain(last) {
+        // Waits replace their node, but be safe about repeated locations anyway
+        if path
+            .last()
+            .is_none_or(|next| next.location != scored_cell.location)
+        {
+            path.push(*scored_cell);
         }
     }
-    out
+    path.reverse();
+    path
 }
 
-// We assume the constraints have already been filtered by unit
-fn satisfies_constraints(scored_cell: &ScoredCell, constraints: &[Constraint]) -> bool {
-    for constraint in constraints {
-        let relevant_cell = constraint.location.intersects(scored_cell.location);
-        let relevant_time =
-        // We haven't left before the constraint begins
-        constraint.duration.0 <= scored_cell.duration.1
-        &&
-        // We didn't arrive after the constraint ended
-        scored_cell.duration.0 <= constraint.duration.1;
-        if relevant_cell && relevant_time {
-            return false;
-        }
-    }
-    true
+// Waypoints left, estimate and straight line distance, lowest is closest
+type Closeness = (usize, usize, usize);
+
+// One waypoint on a unit's route
+#[derive(PartialEq, Eq)]
+struct Leg {
+    goal: Goal,
+    // Cost to reach the waypoint from every origin
+    field: Grid<usize>,
+    // Lower bound on the cost from the waypoint, through the later ones, to a destination
+    rest: usize,
 }
 
-fn open_allows_candidate(candidate: &ScoredCell, open: &BinaryHeap<ScoredCell>) -> bool {
-    for cell in open {
-        // If candidate is cheaper than all remaining cells, we want to check this cell
-        if candidate.cost < cell.cost {
-            break;
-        } else if cell.location == candidate.location && cell.duration == candidate.duration {
-            return false;
-        }
+// What a single search is for
+struct Query<'a> {
+    uid: Pair,
+    faction: Option<Faction>,
+    constraints: &'a UnitConstraints,
+    // Obstacles and enemy occupants, the same for the whole faction
+    occupied: &'a UnitConstraints,
+    zoc: Option<&'a Zoc>,
+    // Waypoints still to pass through, in order, before heading for a destination
+    legs: &'a [Leg],
+    // Past this, no constraint or formation can tell two times apart
+    horizon: usize,
+    // Whether to look for the closest cell when no destination can be reached
+    closest: bool,
+}
+
+// Open list entry, the scored cell itself lives in the arena
+#[derive(PartialEq, Eq)]
+struct OpenNode {
+    scored_cell: ScoredCell,
+    id: NodeId,
+}
+
+impl Ord for OpenNode {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.scored_cell
+            .cmp(&other.scored_cell)
+            .then_with(|| other.id.cmp(&self.id))
     }
-    true
 }
 
-fn may_stop(candidate: &ScoredCell, constraints: &[Constraint]) -> bool {
-    for constraint in constraints {
-        if constraint.location.intersects(candidate.location)
-            && candidate.duration.0 <= constraint.duration.1
-        {
-            return false;
+impl PartialOrd for OpenNode {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// How a search ended. A unit that can't get to any destination is sent as close as it can get
+// instead, when asked for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Route {
+    Reached(Path),
+    Substituted(Path),
+}
+
+impl Route {
+    pub fn path(&self) -> &Path {
+        match self {
+            Route::Reached(path) | Route::Substituted(path) => path,
+        }
+    }
+
+    pub fn into_path(self) -> Path {
+        match self {
+            Route::Reached(path) | Route::Substituted(path) => path,
         }
     }
-    true
+
+    pub fn is_substituted(&self) -> bool {
+        matches!(self, Route::Substituted(_))
+    }
 }
 
-fn reconstruct_path(last: ScoredCell) -> Path {
-    let mut path = Vec::with_capacity(last.duration.1 + 1);
-    path.push(last.clone());
-    let mut prev = last;
-    while let Some(scored_cell) = prev.prev {
-        if prev.location != scored_cell.location {
-            path.push(Rc::unwrap_or_clone(scored_cell.clone()));
+// What a unit does once it reaches its goal
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AtGoal {
+    // Sits there for good, so nobody may pass through later
+    #[default]
+    Stay,
+    // Leaves the map, as when retreating off an edge
+    Disappear,
+    // Holds it until this tick, the end of the turn, then no longer matters
+    HoldUntil(usize),
+}
+
+impl AtGoal {
+    // Last tick a unit that is done at `departure` still occupies its goal
+    pub fn until(self, departure: usize) -> usize {
+        match self {
+            AtGoal::Stay => usize::MAX,
+            AtGoal::Disappear => departure,
+            AtGoal::HoldUntil(tick) => tick.max(departure),
         }
-        prev = Rc::unwrap_or_clone(scored_cell.clone());
     }
-    path.reverse();
-    path
 }
 
 #[derive(PartialEq, Eq)]
@@ -74,8 +144,41 @@ pub struct AStar {
     pub grid: Grid<CellInfo>,
     pub origins: Vec<Pair>,
     pub destinations: Vec<Pair>,
+    // Regions that count as destinations too, any unit may end in any of them
+    pub goals: Vec<Goal>,
     pub unit_extent: Pair,
-    pub heuristic: Grid<usize>,
+    pub movement_class: MovementClass,
+    pub heuristic: Heuristic,
+    // Last tick a unit may still be moving or waiting, none for no limit
+    pub budget: Option<usize>,
+    // Cost of a tick spent waiting, away from the goal and on it
+    pub wait_cost: usize,
+    pub goal_wait_cost: usize,
+    pub at_goal: AtGoal,
+    // Soft cost keeping followers near their place in formation
+    pub formation: Option<FormationKeeping>,
+    // Hard limits on how far followers may get from their leaders, enforced by CBS
+    pub cohesion: Vec<Cohesion>,
+    // What CBS minimises over the whole solution
+    pub objective: Objective,
+    // Goals a unit passes through in order before heading for a destination, by uid. Set through
+    // set_waypoints(), which plans the unit's legs.
+    waypoints: HashMap<Pair, Vec<Goal>>,
+    legs: HashMap<Pair, Vec<Leg>>,
+    // Cells taken on a known schedule by things outside the solve, hard for every unit. Set
+    // through set_obstacles(), which indexes them.
+    obstacles: Vec<Obstacle>,
+    // Side each unit fights for by uid, units without one treat everybody as an enemy. Set
+    // through set_factions(), which indexes what blocks each side.
+    factions: HashMap<Pair, Faction>,
+    // Units outside the solve. Enemies block, allies may be passed through but not stopped on.
+    occupants: Vec<Occupant>,
+    // Everything that blocks a unit of each faction
+    occupied: HashMap<Option<Faction>, UnitConstraints>,
+    // What entering a cell next to an enemy occupant does, none to ignore zones of control. Set
+    // through set_zoc, which rebuilds the zones.
+    zoc: Option<ZocRule>,
+    zones: HashMap<Option<Faction>, Zoc>,
 }
 
 impl AStar {
@@ -100,15 +203,111 @@ impl AStar {
     //    }
     //}
 
-    fn generate_heuristic(&mut self) {
+    fn generate_heuristic(&mut self, cache: &HeuristicCache) {
+        let mut heuristic = Grid::init(self.grid.effective_extent(self.unit_extent), usize::MAX);
         for destination in &self.destinations {
-            let distances = self.grid.djikstra(destination.extend(self.unit_extent));
+            let destination = destination.extend(self.unit_extent);
+            let distances = cache.get(&self.grid, destination, self.movement_class);
             for (pair, cost) in distances.indexed_iter() {
-                if *cost < self.heuristic[pair] {
-                    self.heuristic[pair] = *cost
+                if *cost < heuristic[pair] {
+                    heuristic[pair] = *cost
                 }
             }
         }
+        // Regions share one multi source field, not cached since they rarely repeat
+        let targets: Vec<Rect> = self
+            .goal_origins()
+            .into_iter()
+            .map(|origin| origin.extend(self.unit_extent))
+            .collect();
+        if !targets.is_empty() {
+            let distances = self.grid.djikstra_to_any(&targets);
+            for (pair, cost) in distances.indexed_iter() {
+                if *cost < heuristic[pair] {
+                    heuristic[pair] = *cost
+                }
+            }
+        }
+        self.heuristic = Heuristic::Exact(heuristic);
+    }
+
+    fn goal_origins(&self) -> Vec<Pair> {
+        let mut out: Vec<Pair> = self
+            .goals
+            .iter()
+            .flat_map(|goal| goal.origins(&self.grid, self.unit_extent))
+            .collect();
+        out.sort();
+        out.dedup();
+        out
+    }
+
+    // Built backwards, each waypoint's rest is the cheapest way through it to the next one
+    fn plan_legs(&self, waypoints: &[Goal]) -> Vec<Leg> {
+        let mut out: Vec<Leg> = Vec::with_capacity(waypoints.len());
+        for goal in waypoints.iter().rev() {
+            let origins = goal.origins(&self.grid, self.unit_extent);
+            let rest = origins
+                .iter()
+                .map(|origin| match out.last() {
+                    Some(next) => next.field[*origin].saturating_add(next.rest),
+                    None => self.heuristic.estimate(*origin),
+                })
+                .min()
+                .unwrap_or(usize::MAX);
+            let targets: Vec<Rect> = origins
+                .into_iter()
+                .map(|origin| origin.extend(self.unit_extent))
+                .collect();
+            let field = if targets.is_empty() {
+                Grid::init(self.grid.effective_extent(self.unit_extent), usize::MAX)
+            } else {
+                self.grid.djikstra_to_any(&targets)
+            };
+            out.push(Leg {
+                goal: goal.clone(),
+                field,
+                rest,
+            });
+        }
+        out.reverse();
+        out
+    }
+
+    fn plan_waypoints(&mut self) {
+        self.legs = self
+            .waypoints
+            .iter()
+            .map(|(uid, waypoints)| (*uid, self.plan_legs(waypoints)))
+            .collect();
+    }
+
+    // Replaces the unit's waypoints, an empty list sends it straight to a destination
+    pub fn set_waypoints(&mut self, uid: Pair, waypoints: Vec<Goal>) {
+        if waypoints.is_empty() {
+            self.waypoints.remove(&uid);
+            self.legs.remove(&uid);
+            return;
+        }
+        let legs = self.plan_legs(&waypoints);
+        self.waypoints.insert(uid, waypoints);
+        self.legs.insert(uid, legs);
+    }
+
+    pub fn waypoints(&self, uid: Pair) -> &[Goal] {
+        self.waypoints.get(&uid).map_or(&[], Vec::as_slice)
+    }
+
+    // Every origin a search may end on
+    fn targets(&self) -> Vec<Pair> {
+        let mut out = self.goal_origins();
+        out.extend(self.destinations.iter().copied());
+        out
+    }
+
+    fn is_destination(&self, location: Rect) -> bool {
+        self.destinations.contains(&location.origin)
+            || self.goals.iter().any(|goal| goal.contains(location))
     }
 
     //fn verify_connectivity(&self) {
@@ -132,11 +331,28 @@ impl AStar {
         grid: Grid<CellInfo>,
     ) -> AStar {
         AStar {
-            heuristic: Grid::init(grid.effective_extent(unit_extent), usize::MAX),
+            heuristic: Heuristic::Exact(Grid::init(grid.effective_extent(unit_extent), usize::MAX)),
             unit_extent,
+            movement_class: MovementClass::default(),
+            budget: None,
+            wait_cost: 1,
+            goal_wait_cost: 1,
+            at_goal: AtGoal::default(),
+            formation: None,
+            cohesion: Vec::new(),
+            objective: Objective::default(),
             grid,
             origins,
             destinations,
+            goals: Vec::new(),
+            waypoints: HashMap::new(),
+            legs: HashMap::new(),
+            obstacles: Vec::new(),
+            factions: HashMap::new(),
+            occupants: Vec::new(),
+            occupied: HashMap::from([(None, UnitConstraints::default())]),
+            zoc: None,
+            zones: HashMap::new(),
         }
     }
 
@@ -145,73 +361,763 @@ impl AStar {
         destinations: Vec<Pair>,
         unit_extent: Pair,
         grid: Grid<CellInfo>,
+    ) -> AStar {
+        AStar::init_cached(
+            origins,
+            destinations,
+            unit_extent,
+            grid,
+            &HeuristicCache::new(),
+        )
+    }
+
+    // As init(), reusing distance fields computed by earlier solvers
+    pub fn init_cached(
+        origins: Vec<Pair>,
+        destinations: Vec<Pair>,
+        unit_extent: Pair,
+        grid: Grid<CellInfo>,
+        cache: &HeuristicCache,
     ) -> AStar {
         let mut out = AStar::new(origins, destinations, unit_extent, grid);
         //out.verify_destination_count();
         //out.verify_cells(&origins);
         //out.verify_cells(&destinations);
         //out.verify_connectivity();
-        out.generate_heuristic();
+        out.generate_heuristic(cache);
+        out
+    }
+
+    // Skips the per destination Djikstras, for many destinations or ones that keep changing.
+    // The landmarks must have been placed for `unit_extent`.
+    pub fn init_with_landmarks(
+        origins: Vec<Pair>,
+        destinations: Vec<Pair>,
+        unit_extent: Pair,
+        grid: Grid<CellInfo>,
+        landmarks: Arc<Landmarks>,
+    ) -> AStar {
+        if landmarks.extent() != unit_extent {
+            panic!(
+                "Landmarks for extent {:?} used with extent {:?}!",
+                landmarks.extent(),
+                unit_extent
+            )
+        }
+        let mut out = AStar::new(origins, destinations, unit_extent, grid);
+        let bounds = landmarks.bounds(&out.targets());
+        out.heuristic = Heuristic::Landmarks(landmarks, bounds);
+        out
+    }
+
+    // Call after changing the grid, destinations, goals or movement class. The setters keep
+    // waypoints, obstacles, occupants, factions and the ZOC rule current by themselves.
+    pub fn refresh_heuristic(&mut self, cache: &HeuristicCache) {
+        match &self.heuristic {
+            Heuristic::Exact(_) => self.generate_heuristic(cache),
+            Heuristic::Landmarks(landmarks, _) => {
+                let landmarks = if landmarks.is_current(&self.grid) {
+                    Arc::clone(landmarks)
+                } else {
+                    Arc::new(landmarks.rebuild(&self.grid))
+                };
+                let bounds = landmarks.bounds(&self.targets());
+                self.heuristic = Heuristic::Landmarks(landmarks, bounds);
+            }
+        }
+        self.plan_waypoints();
+        self.index_occupancy();
+    }
+
+    // Replaces the obstacles without recomputing any heuristic, as they don't affect one. Only
+    // A* and CBS see them, see the TODO on PIBT.
+    pub fn set_obstacles(&mut self, obstacles: Vec<Obstacle>) {
+        self.obstacles = obstacles;
+        self.index_occupancy();
+    }
+
+    pub fn obstacles(&self) -> &[Obstacle] {
+        &self.obstacles
+    }
+
+    // As set_obstacles(), for the units standing around
+    pub fn set_occupants(&mut self, occupants: Vec<Occupant>) {
+        self.occupants = occupants;
+        self.index_occupancy();
+    }
+
+    pub fn occupants(&self) -> &[Occupant] {
+        &self.occupants
+    }
+
+    // As set_obstacles(), for the sides of the units being solved
+    pub fn set_factions(&mut self, factions: HashMap<Pair, Faction>) {
+        self.factions = factions;
+        self.index_occupancy();
+    }
+
+    pub fn faction(&self, uid: Pair) -> Option<Faction> {
+        self.factions.get(&uid).copied()
+    }
+
+    pub fn zoc(&self) -> Option<ZocRule> {
+        self.zoc
+    }
+
+    pub fn set_zoc(&mut self, zoc: Option<ZocRule>) {
+        self.zoc = zoc;
+        self.index_occupancy();
+    }
+
+    fn index_occupancy(&mut self) {
+        let factions: HashSet<Option<Faction>> = self
+            .factions
+            .values()
+            .copied()
+            .map(Some)
+            .chain([None])
+            .collect();
+        self.occupied = factions
+            .iter()
+            .map(|faction| (*faction, self.blocking(*faction)))
+            .collect();
+        self.zones = match self.zoc {
+            Some(rule) => factions
+                .into_iter()
+                .map(|faction| {
+                    (
+                        faction,
+                        Zoc::new(&self.grid, faction, &self.occupants, rule),
+                    )
+                })
+                .collect(),
+            None => HashMap::new(),
+        };
+    }
+
+    // Enemies never move out of the way
+    fn blocking(&self, faction: Option<Faction>) -> UnitConstraints {
+        let mut out = UnitConstraints::from_obstacles(&self.obstacles);
+        for occupant in &self.occupants {
+            if !Faction::allied(Some(occupant.faction), faction) {
+                out.avoid(occupant.location, Pair(0, usize::MAX));
+            }
+        }
         out
     }
 
-    fn successors(&self, scored_cell: ScoredCell, constraints: &[Constraint]) -> Vec<ScoredCell> {
+    // Whether `location` overlaps an ally other than the unit itself
+    fn on_ally(&self, location: Rect, query: &Query) -> bool {
+        let start = query.uid.extend(self.unit_extent);
+        self.occupants.iter().any(|occupant| {
+            Faction::allied(Some(occupant.faction), query.faction)
+                && occupant.location != start
+                && occupant.location.intersects(location)
+        })
+    }
+
+    pub fn retarget(&mut self, destinations: Vec<Pair>, cache: &HeuristicCache) {
+        self.destinations = destinations;
+        self.refresh_heuristic(cache);
+    }
+
+    pub fn retarget_goals(&mut self, goals: Vec<Goal>, cache: &HeuristicCache) {
+        self.goals = goals;
+        self.refresh_heuristic(cache);
+    }
+
+    // Through the remaining waypoints, then to the nearest destination
+    fn remaining(&self, origin: Pair, leg: usize, query: &Query) -> usize {
+        match query.legs.get(leg) {
+            Some(next) => next.field[origin].saturating_add(next.rest),
+            None => self.heuristic.estimate(origin),
+        }
+    }
+
+    // None where no destination can be reached. Searching for the closest cell walks there too,
+    // with nothing left to estimate.
+    fn estimate(&self, origin: Pair, leg: usize, query: &Query) -> Option<usize> {
+        match self.remaining(origin, leg, query) {
+            usize::MAX if query.closest => Some(0),
+            usize::MAX => None,
+            estimate => Some(estimate),
+        }
+    }
+
+    // Waypoints reached once `location` is entered with `leg` of them behind
+    fn advance(&self, leg: usize, location: Rect, query: &Query) -> usize {
+        let mut leg = leg;
+        while query
+            .legs
+            .get(leg)
+            .is_some_and(|next| next.goal.contains(location))
+        {
+            leg += 1;
+        }
+        leg
+    }
+
+    // Cost is what was spent, on moves, waits and formation penalties, plus the estimate
+    fn spent(&self, scored_cell: &ScoredCell, query: &Query) -> usize {
+        let estimate = self
+            .estimate(scored_cell.location.origin, scored_cell.leg, query)
+            .unwrap_or(0);
+        scored_cell.cost.saturating_sub(estimate)
+    }
+
+    fn is_done(&self, scored_cell: &ScoredCell, query: &Query) -> bool {
+        scored_cell.leg == query.legs.len() && self.is_destination(scored_cell.location)
+    }
+
+    fn penalty(&self, uid: Pair, location: Rect, duration: Pair) -> usize {
+        self.formation.as_ref().map_or(0, |formation| {
+            formation.penalty(uid, location.origin, duration)
+        })
+    }
+
+    fn wait_cost(&self, scored_cell: &ScoredCell, query: &Query) -> usize {
+        if self.is_done(scored_cell, query) {
+            self.goal_wait_cost
+        } else {
+            self.wait_cost
+        }
+    }
+
+    fn allows(&self, scored_cell: &ScoredCell, query: &Query) -> bool {
+        query.constraints.allows(scored_cell) && query.occupied.allows(scored_cell)
+    }
+
+    fn may_stay(&self, scored_cell: &ScoredCell, until: usize, query: &Query) -> bool {
+        query.constraints.may_stay(scored_cell, until)
+            && query.occupied.may_stay(scored_cell, until)
+            && !self.on_ally(scored_cell.location, query)
+    }
+
+    fn may_stop(&self, scored_cell: &ScoredCell, query: &Query) -> bool {
+        query.constraints.may_stop(scored_cell)
+            && query.occupied.may_stop(scored_cell)
+            && !self.on_ally(scored_cell.location, query)
+    }
+
+    // Successors paired with the node they should point back to
+    fn successors(
+        &self,
+        arena: &Arena<ScoredCell>,
+        id: NodeId,
+        query: &Query,
+    ) -> Vec<(ScoredCell, Option<NodeId>)> {
+        let scored_cell = arena.get(id);
         let neighbors = self.grid.neighbors(scored_cell.location);
         let mut succ = Vec::with_capacity(neighbors.len() + 1);
-        let wait = ScoredCell {
-            cost: scored_cell.cost + 1,
-            duration: Pair(scored_cell.duration.0, scored_cell.duration.1 + 1),
-            location: scored_cell.location,
-            prev: scored_cell.prev.clone(),
-        };
-        if satisfies_constraints(&wait, constraints) {
-            succ.push(wait);
+        // Waiting past the last constraint never opens up a better path
+        let within_budget = self
+            .budget
+            .is_none_or(|budget| scored_cell.duration.1 < budget);
+        if scored_cell.duration.1 <= query.horizon && within_budget {
+            let tick = scored_cell.duration.1 + 1;
+            let penalty = self.penalty(query.uid, scored_cell.location, Pair(tick, tick));
+            let wait = ScoredCell {
+                cost: scored_cell.cost + self.wait_cost(scored_cell, query) + penalty,
+                duration: Pair(scored_cell.duration.0, tick),
+                location: scored_cell.location,
+                leg: scored_cell.leg,
+            };
+            if self.allows(&wait, query) {
+                // A wait replaces the cell it extends
+                succ.push((wait, arena.prev(id)));
+            }
         }
-        let sc = Rc::new(scored_cell);
+        let spent = self.spent(scored_cell, query);
         for location in neighbors {
-            let time = sc.duration.1 + self.grid.cost(location);
-            let candidate = ScoredCell {
-                cost: time + self.heuristic[location.origin],
-                duration: Pair(sc.duration.1 + 1, time),
+            let leg = self.advance(scored_cell.leg, location, query);
+            let Some(heuristic) = self.estimate(location.origin, leg, query) else {
+                continue;
+            };
+            let toll = query.zoc.map_or(0, |zoc| zoc.toll(location));
+            let step = self.grid.cost(location) + toll;
+            let time = scored_cell.duration.1 + step;
+            if self.budget.is_some_and(|budget| time > budget) {
+                continue;
+            }
+            // Stopped by a zone of control, the unit sits out the rest of the turn
+            let held = query.zoc.map_or(time, |zoc| zoc.hold(location, time));
+            if self.budget.is_some_and(|budget| held > budget) {
+                continue;
+            }
+            let duration = Pair(scored_cell.duration.1 + 1, held);
+            let penalty = self.penalty(query.uid, location, duration);
+            let mut candidate = ScoredCell {
+                cost: spent + step + penalty + heuristic,
+                duration,
                 location,
-                prev: Some(Rc::clone(&sc)),
+                leg,
             };
-            if satisfies_constraints(&candidate, constraints) {
-                succ.push(candidate);
+            candidate.cost += (held - time) * self.wait_cost(&candidate, query);
+            if self.allows(&candidate, query) {
+                succ.push((candidate, Some(id)));
             }
         }
         succ
     }
 
     pub fn astar(&self, start: Pair, constraints: &[Constraint]) -> Option<Path> {
-        let my_constraints = filter_constraints(start, constraints);
+        self.astar_constrained(start, &UnitConstraints::new(start, constraints))
+    }
+
+    // As astar(), with the unit's constraints already indexed
+    pub fn astar_constrained(&self, start: Pair, constraints: &UnitConstraints) -> Option<Path> {
+        self.search(start, constraints, false)
+            .map(|route| route.into_path())
+    }
+
+    // As astar(), but a unit that can't reach any destination, or can't within the budget, is
+    // routed to where it gets closest instead
+    pub fn astar_or_closest(&self, start: Pair, constraints: &[Constraint]) -> Option<Route> {
+        self.astar_constrained_or_closest(start, &UnitConstraints::new(start, constraints))
+    }
+
+    pub fn astar_constrained_or_closest(
+        &self,
+        start: Pair,
+        constraints: &UnitConstraints,
+    ) -> Option<Route> {
+        self.search(start, constraints, true)
+    }
+
+    // Ranks stopping places by waypoints left, then estimated cost to the nearest destination.
+    // Where that is unknown, as in a region the destinations can't be reached from, by straight
+    // line distance to the next waypoint or destination.
+    fn closeness(&self, scored_cell: &ScoredCell, query: &Query) -> Closeness {
+        let location = scored_cell.location;
+        let origin = location.origin;
+        let estimate = self.remaining(origin, scored_cell.leg, query);
+        let left = query.legs.len() - scored_cell.leg;
+        if let Some(next) = query.legs.get(scored_cell.leg) {
+            let distance = next.goal.distance(location).unwrap_or(usize::MAX);
+            return (left, estimate, distance);
+        }
+        let to_cells = self
+            .destinations
+            .iter()
+            .map(|to| origin.0.abs_diff(to.0) + origin.1.abs_diff(to.1));
+        let to_goals = self.goals.iter().filter_map(|goal| goal.distance(location));
+        let distance = to_cells.chain(to_goals).min().unwrap_or(usize::MAX);
+        (left, estimate, distance)
+    }
+
+    fn search(&self, start: Pair, constraints: &UnitConstraints, closest: bool) -> Option<Route> {
+        let formation_horizon = self
+            .formation
+            .as_ref()
+            .filter(|formation| formation.is_follower(start))
+            .map_or(0, |formation| formation.horizon());
+        let faction = self.faction(start);
+        // Every faction is indexed when set, factionless units are always there to fall back on
+        let occupied = self.occupied.get(&faction).unwrap_or(&self.occupied[&None]);
+        let horizon = constraints
+            .horizon()
+            .max(occupied.horizon())
+            .max(formation_horizon);
+        let query = Query {
+            uid: start,
+            faction,
+            constraints,
+            occupied,
+            zoc: self.zones.get(&faction).or(self.zones.get(&None)),
+            legs: self.legs.get(&start).map_or(&[], Vec::as_slice),
+            horizon,
+            closest,
+        };
         let Pair(x_extent, y_extent) = self.grid.effective_size(self.unit_extent);
+        let mut arena = Arena::with_capacity(x_extent * y_extent);
         let mut open = BinaryHeap::with_capacity(x_extent * y_extent);
-        open.push(ScoredCell {
-            cost: 0,
+        // Cheapest cost each state has been pushed with, so we never push a worse copy
+        let mut best: HashMap<StateKey, usize> = HashMap::with_capacity(x_extent * y_extent);
+        let mut closed: HashSet<StateKey> = HashSet::with_capacity(x_extent * y_extent);
+        // Where to stop if no destination can be reached, when looking for the closest cell
+        let mut best_stop: Option<((Closeness, usize), NodeId)> = None;
+        let location = start.extend(self.unit_extent);
+        let leg = self.advance(0, location, &query);
+        let start = ScoredCell {
+            cost: self.estimate(start, leg, &query).unwrap_or(0),
             duration: Pair(0, 0),
-            location: start.extend(self.unit_extent),
-            prev: None,
+            location,
+            leg,
+        };
+        best.insert(state_key(&start, horizon), start.cost);
+        let id = arena.push(start, None);
+        open.push(OpenNode {
+            scored_cell: start,
+            id,
         });
 
-        loop {
-            let current = match open.pop() {
-                None => {
-                    return None;
+        while let Some(OpenNode {
+            scored_cell: current,
+            id,
+        }) = open.pop()
+        {
+            // Stale copies of states that were pushed again more cheaply
+            if !closed.insert(state_key(&current, horizon)) {
+                continue;
+            }
+            let until = self.at_goal.until(current.duration.1);
+            if self.is_done(&current, &query) && self.may_stay(&current, until, &query) {
+                return Some(Route::Reached(reconstruct_path(&arena, id)));
+            }
+            if closest && self.may_stop(&current, &query) {
+                let rank = (self.closeness(&current, &query), current.duration.1);
+                if best_stop.is_none_or(|(best_rank, _)| rank < best_rank) {
+                    best_stop = Some((rank, id));
                 }
-                Some(sc) => sc,
-            };
-            for successor in self.successors(current, &my_constraints) {
-                if open_allows_candidate(&successor, &open) {
-                    if self.destinations.contains(&successor.location.origin)
-                        && may_stop(&successor, &my_constraints)
-                    {
-                        let path = reconstruct_path(successor);
-                        return Some(path);
+            }
+            for (successor, prev) in self.successors(&arena, id, &query) {
+                let key = state_key(&successor, horizon);
+                if closed.contains(&key) {
+                    continue;
+                }
+                let improves = best.get(&key).is_none_or(|cost| successor.cost < *cost);
+                if improves {
+                    best.insert(key, successor.cost);
+                    let id = arena.push(successor, prev);
+                    open.push(OpenNode {
+                        scored_cell: successor,
+                        id,
+                    });
+                }
+            }
+        }
+        best_stop.map(|(_, id)| Route::Substituted(reconstruct_path(&arena, id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn arrival(path: &Path) -> (Pair, usize) {
+        let last = path[path.len() - 1];
+        (last.location.origin, last.duration.0)
+    }
+
+    #[test]
+    fn walled_off_goals_fall_back_to_the_closest_cell() {
+        let grid = testing::parse(&[
+            "........#...",
+            "........#...",
+            "........#...",
+            "........#...",
+        ]);
+        let exact = grid.djikstra_from(Pair(0, 0).extend(Pair(0, 0)));
+        let astar = AStar::init(vec![Pair(0, 0)], vec![Pair(10, 2)], Pair(0, 0), grid);
+        assert_eq!(astar.astar(Pair(0, 0), &[]), None);
+        let route = astar.astar_or_closest(Pair(0, 0), &[]).unwrap();
+        assert!(route.is_substituted());
+        // Nearest in a straight line, as no estimate crosses the wall
+        assert_eq!(arrival(route.path()), (Pair(7, 2), exact[Pair(7, 2)]));
+    }
+
+    #[test]
+    fn budgets_stop_short_at_the_best_estimate() {
+        let grid = testing::maze();
+        let from = grid.djikstra_from(Pair(0, 0).extend(Pair(0, 0)));
+        let to = grid.djikstra(Pair(11, 8).extend(Pair(0, 0)));
+        let mut astar = AStar::init(vec![Pair(0, 0)], vec![Pair(11, 8)], Pair(0, 0), grid);
+        let route = astar.astar_or_closest(Pair(0, 0), &[]).unwrap();
+        assert!(!route.is_substituted());
+        assert_eq!(arrival(route.path()), (Pair(11, 8), from[Pair(11, 8)]));
+        for budget in [0, 4, 10, from[Pair(11, 8)] - 1] {
+            astar.budget = Some(budget);
+            assert_eq!(astar.astar(Pair(0, 0), &[]), None);
+            let route = astar.astar_or_closest(Pair(0, 0), &[]).unwrap();
+            assert!(route.is_substituted());
+            let (end, time) = arrival(route.path());
+            assert!(time <= budget);
+            let best = testing::clear_origins(&astar.grid, Pair(0, 0))
+                .into_iter()
+                .filter(|origin| from[*origin] <= budget)
+                .map(|origin| to[origin])
+                .min()
+                .unwrap();
+            assert_eq!(to[end], best, "budget {}", budget);
+        }
+    }
+
+    #[test]
+    fn waypoint_routes_cost_the_sum_of_their_legs() {
+        let grid = testing::maze();
+        let point = |origin: Pair| origin.extend(Pair(0, 0));
+        let start = Pair(0, 0);
+        let area = Pair(4, 4).extend(Pair(1, 2));
+        let waypoints = vec![Goal::Area(area), Goal::Cell(Pair(11, 8))];
+        let from_start = grid.djikstra_from(point(start));
+        let to_cell = grid.djikstra(point(Pair(11, 8)));
+        let last_leg = grid.djikstra(point(Pair(0, 8)))[Pair(11, 8)];
+        let exact = waypoints[0]
+            .origins(&grid, Pair(0, 0))
+            .into_iter()
+            .map(|origin| from_start[origin] + to_cell[origin])
+            .min()
+            .unwrap()
+            + last_leg;
+        let mut astar = AStar::init(vec![start], vec![Pair(0, 8)], Pair(0, 0), grid);
+        let plain = astar.astar(start, &[]);
+        astar.set_waypoints(start, waypoints.clone());
+        assert_eq!(astar.waypoints(start), waypoints.as_slice());
+        let path = astar.astar(start, &[]).unwrap();
+        let locations: Vec<Rect> = path.iter().map(|cell| cell.location).collect();
+        assert_eq!(testing::walk_cost(&astar.grid, &locations), exact);
+        // Each leg starts where its waypoint was reached
+        for (leg, goal) in waypoints.iter().enumerate() {
+            let reached = path.iter().find(|cell| cell.leg > leg).unwrap();
+            assert!(goal.contains(reached.location));
+        }
+        astar.set_waypoints(start, Vec::new());
+        assert_eq!(astar.astar(start, &[]), plain);
+    }
+
+    #[test]
+    fn waits_cost_what_they_are_set_to_and_goals_are_held_as_asked() {
+        let corridor = || testing::parse(&["......"]);
+        let point = |x: usize| Pair(x, 0).extend(Pair(0, 0));
+        let mut astar = AStar::init(vec![Pair(0, 0)], vec![Pair(5, 0)], Pair(0, 0), corridor());
+        let exact = corridor().djikstra_from(point(0))[Pair(5, 0)];
+        // The only way out is closed until tick 6, so the unit can't pace instead of waiting
+        let closed = Constraint::Avoid {
+            uid: Pair(0, 0),
+            location: point(1),
+            duration: Pair(0, 6),
+        };
+        for wait_cost in [0, 1, 3] {
+            astar.wait_cost = wait_cost;
+            let path = astar.astar(Pair(0, 0), &[closed]).unwrap();
+            let last = path[path.len() - 1];
+            let waits = last.duration.0 - exact;
+            assert_eq!(waits, 6);
+            assert_eq!(last.cost, exact + waits * wait_cost);
+        }
+        // Something comes through the goal at tick 20
+        let later = Constraint::Avoid {
+            uid: Pair(0, 0),
+            location: point(5),
+            duration: Pair(20, 20),
+        };
+        // Units that would still be holding it then have to come after
+        for (at_goal, arrival) in [
+            (AtGoal::Stay, 21),
+            (AtGoal::Disappear, exact),
+            (AtGoal::HoldUntil(10), exact),
+            (AtGoal::HoldUntil(25), 21),
+        ] {
+            astar.at_goal = at_goal;
+            let path = astar.astar(Pair(0, 0), &[later]).unwrap();
+            assert_eq!(path[path.len() - 1].duration.0, arrival, "{:?}", at_goal);
+        }
+    }
+
+    #[test]
+    fn obstacles_block_every_unit_on_their_schedule() {
+        let grid = testing::maze();
+        let origins = vec![Pair(0, 0), Pair(11, 0), Pair(0, 6)];
+        let destinations = vec![Pair(11, 8), Pair(4, 4), Pair(9, 2)];
+        // A wall that never clears is the same as a blocked cell, here closing the top left's
+        // way through the maze
+        let wall = Pair(5, 1).extend(Pair(0, 0));
+        let mut walled = testing::maze();
+        walled.set_blocked(wall, true);
+        let mut astar = AStar::init(origins.clone(), destinations.clone(), Pair(0, 0), grid);
+        astar.set_obstacles(vec![Obstacle {
+            location: wall,
+            duration: Pair(0, usize::MAX),
+        }]);
+        let plain = AStar::init(origins.clone(), destinations.clone(), Pair(0, 0), walled);
+        for origin in &origins {
+            let path = astar.astar(*origin, &[]).unwrap();
+            let expected = plain.astar(*origin, &[]).unwrap();
+            assert_eq!(
+                path[path.len() - 1].duration,
+                expected[expected.len() - 1].duration
+            );
+        }
+        // Something walking a route is the same as a unit told to avoid each step of it
+        let walker = plain.astar(Pair(0, 6), &[]).unwrap();
+        astar.set_obstacles(Obstacle::along(&walker));
+        assert_eq!(astar.obstacles().len(), walker.len());
+        let avoid: Vec<Constraint> = walker
+            .iter()
+            .map(|scored_cell| Constraint::Avoid {
+                uid: Pair(0, 0),
+                location: scored_cell.location,
+                duration: scored_cell.duration,
+            })
+            .collect();
+        let free = AStar::init(origins, destinations, Pair(0, 0), testing::maze());
+        assert_eq!(astar.astar(Pair(0, 0), &[]), free.astar(Pair(0, 0), &avoid));
+        // It does get in the way
+        assert_ne!(astar.astar(Pair(0, 0), &[]), free.astar(Pair(0, 0), &[]));
+    }
+
+    #[test]
+    fn allies_are_passed_through_and_enemies_walled_off() {
+        let corridor = || testing::parse(&["......", "#.####"]);
+        let point = |x: usize| Pair(x, 0).extend(Pair(0, 0));
+        let mut astar = AStar::init(vec![Pair(0, 0)], vec![Pair(5, 0)], Pair(0, 0), corridor());
+        astar.set_factions(HashMap::from([(Pair(0, 0), Faction(0))]));
+        let exact = corridor().djikstra_from(point(0))[Pair(5, 0)];
+        let arrival = |astar: &AStar| {
+            let path = astar.astar(Pair(0, 0), &[])?;
+            Some(path[path.len() - 1].duration.0)
+        };
+        astar.set_occupants(vec![Occupant {
+            location: point(3),
+            faction: Faction(0),
+        }]);
+        assert_eq!(arrival(&astar), Some(exact));
+        // Nobody stops on an ally, so the destination under one is out of reach
+        astar.set_occupants(vec![Occupant {
+            location: point(5),
+            faction: Faction(0),
+        }]);
+        assert_eq!(arrival(&astar), None);
+        astar.set_occupants(vec![Occupant {
+            location: point(3),
+            faction: Faction(1),
+        }]);
+        assert_eq!(arrival(&astar), None);
+        // Without a faction, the unit has no allies
+        astar.set_factions(HashMap::new());
+        astar.set_occupants(vec![Occupant {
+            location: point(3),
+            faction: Faction(0),
+        }]);
+        assert_eq!(arrival(&astar), None);
+    }
+
+    #[test]
+    fn zones_of_control_toll_or_hold_to_the_end_of_the_turn() {
+        // The enemy at (6, 1) projects onto (5, 1), (7, 1) and (6, 0)
+        let corridor = testing::parse(&["............", "444444444444"]);
+        let tolled = testing::parse(&["......4.....", "444447#74444"]);
+        let mut astar = AStar::init(vec![Pair(0, 0)], vec![Pair(11, 0)], Pair(0, 0), corridor);
+        astar.set_factions(HashMap::from([(Pair(0, 0), Faction(0))]));
+        astar.set_occupants(vec![Occupant {
+            location: Pair(6, 1).extend(Pair(0, 0)),
+            faction: Faction(1),
+        }]);
+        let arrive = |astar: &AStar| arrival(&astar.astar(Pair(0, 0), &[]).unwrap()).1;
+        assert_eq!(arrive(&astar), 11);
+        astar.set_zoc(Some(ZocRule::Toll(3)));
+        let exact = tolled.djikstra_from(Pair(0, 0).extend(Pair(0, 0)));
+        assert_eq!(arrive(&astar), exact[Pair(11, 0)]);
+        // Done entering (6, 0) at tick 6, the unit sits out the turn that tick falls in
+        for (turn, held) in [(4, 7), (5, 9), (6, 11), (7, 6)] {
+            astar.set_zoc(Some(ZocRule::Stop { turn }));
+            assert_eq!(arrive(&astar), held + 5, "turn {}", turn);
+        }
+        // A hold running past the budget keeps the unit out of the zone
+        astar.set_zoc(Some(ZocRule::Stop { turn: 6 }));
+        for (budget, end) in [(10, Pair(5, 0)), (11, Pair(6, 0))] {
+            astar.budget = Some(budget);
+            let route = astar.astar_or_closest(Pair(0, 0), &[]).unwrap();
+            assert_eq!(arrival(route.path()).0, end, "budget {}", budget);
+        }
+    }
+
+    // Earliest tick a single unit can be done at `goal` for good, by flooding (cell, tick) states
+    // one tick at a time until well past the last constraint
+    fn time_expanded(
+        grid: &Grid<CellInfo>,
+        start: Pair,
+        goal: Pair,
+        constraints: &[Constraint],
+    ) -> Option<usize> {
+        let point = |cell: Pair| cell.extend(Pair(0, 0));
+        let free = |cell: Pair, time: usize| {
+            constraints.iter().all(|constraint| match *constraint {
+                Constraint::Avoid {
+                    location, duration, ..
+                } => !location.contains(cell) || time < duration.0 || time > duration.1,
+                Constraint::Near {
+                    location,
+                    radius,
+                    time: at,
+                    ..
+                } => at != time || point(cell).distance(location) <= radius,
+                Constraint::Leave {
+                    location, time: at, ..
+                } => at != time || point(cell) != location,
+            })
+        };
+        let horizon = UnitConstraints::new(start, constraints).horizon();
+        let end = horizon + 4 * grid.size().0 * grid.size().1;
+        let mut at: Vec<HashSet<Pair>> = vec![HashSet::new(); end + 1];
+        at[0].insert(start);
+        for time in 0..end {
+            let cells: Vec<Pair> = at[time].iter().copied().collect();
+            for cell in cells {
+                if cell == goal && (time..=horizon + 1).all(|tick| free(goal, tick)) {
+                    return Some(time);
+                }
+                if free(cell, time + 1) {
+                    at[time + 1].insert(cell);
+                }
+                for next in grid.neighbors(point(cell)) {
+                    let done = time + grid.cost(next);
+                    if done <= end && (time + 1..=done).all(|tick| free(next.origin, tick)) {
+                        at[done].insert(next.origin);
                     }
-                    open.push(successor);
                 }
             }
         }
+        None
+    }
+
+    #[test]
+    fn constrained_arrivals_match_a_time_expanded_search() {
+        let mut rng = StdRng::seed_from_u64(28);
+        for seed in 0..60 {
+            let grid = testing::random(seed, Pair(6, 4), 0.15, 3);
+            let clear = testing::clear_origins(&grid, Pair(0, 0));
+            let start = clear[rng.gen_range(0..clear.len())];
+            let goal = clear[rng.gen_range(0..clear.len())];
+            let uid = start;
+            let mut constraints = Vec::new();
+            for _ in 0..rng.gen_range(0..12) {
+                let location = clear[rng.gen_range(0..clear.len())].extend(Pair(0, 0));
+                // From early on to well past where the states start to collapse
+                let time = rng.gen_range(1..=24);
+                constraints.push(match rng.gen_range(0..10) {
+                    0 => Constraint::Near {
+                        uid,
+                        location,
+                        radius: rng.gen_range(2..=6),
+                        time,
+                    },
+                    1 | 2 => Constraint::Leave {
+                        uid,
+                        location,
+                        time,
+                    },
+                    3 => Constraint::Avoid {
+                        uid,
+                        location,
+                        duration: Pair(time, usize::MAX),
+                    },
+                    _ => Constraint::Avoid {
+                        uid,
+                        location,
+                        duration: Pair(time, time + rng.gen_range(0..6)),
+                    },
+                });
+            }
+            let astar = AStar::init(vec![start], vec![goal], Pair(0, 0), grid);
+            let done = astar
+                .astar(start, &constraints)
+                .map(|path| path[path.len() - 1].duration.1);
+            let exact = time_expanded(&astar.grid, start, goal, &constraints);
+            assert_eq!(done, exact, "seed {} under {:?}", seed, constraints);
+        }
     }
 }
@@ -1,19 +1,43 @@
-use crate::astar::AStar;
+use crate::astar::{AStar, AtGoal};
+use crate::constraints::{ConstraintTable, UnitConstraints};
 use crate::prelude::{Path, *};
+use crate::worker::CancelToken;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 use std::collections::BinaryHeap;
+use std::sync::Arc;
 
 struct UnitState {
     uid: Pair,
+    faction: Option<Faction>,
     path_idx: usize,
     location: Rect,
     duration: Pair,
+    // Not moving this tick: waiting on the way, or on the last cell of its path
+    still: bool,
+    // Done with its path, and no longer on the map as far as this plan cares
+    gone: bool,
+}
+
+impl UnitState {
+    fn is_ally(&self, other: &UnitState) -> bool {
+        Faction::allied(self.faction, other.faction)
+    }
+
+    // Allies only get in each other's way by standing in the same place
+    fn blocks(&self, other: &UnitState) -> bool {
+        !self.is_ally(other) || (self.still && other.still)
+    }
 }
 
 #[derive(Clone, PartialEq, Eq)]
 pub struct CBS<'a> {
     pub astar: &'a AStar,
     pub constraints: Vec<Constraint>,
-    pub solution: Vec<Path>,
+    // The same constraints, indexed for the low level search
+    pub table: ConstraintTable,
+    // Shared between nodes, a path is only copied when a node changes it
+    pub solution: Vec<Arc<Path>>,
     pub cost: usize,
     pub conflicts: Vec<Conflict>,
 }
@@ -43,6 +67,7 @@ impl CBS<'_> {
         CBS {
             astar,
             constraints: Vec::new(),
+            table: ConstraintTable::default(),
             solution: Vec::with_capacity(astar.origins.len()),
             cost: 0,
             conflicts: Vec::new(),
@@ -59,15 +84,23 @@ impl CBS<'_> {
     }
 
     fn find_paths(&mut self) {
-        for cell in &self.astar.origins {
-            let path = self
-                .astar
-                .astar(*cell, &self.constraints)
-                .expect("Unable to find preliminary path!");
-            self.solution.push(path);
-        }
+        #[cfg(feature = "rayon")]
+        let origins = self.astar.origins.par_iter();
+        #[cfg(not(feature = "rayon"))]
+        let origins = self.astar.origins.iter();
+        self.solution = origins
+            .map(|cell| {
+                let path = self
+                    .astar
+                    .astar_constrained(*cell, &UnitConstraints::default())
+                    .expect("Unable to find preliminary path!");
+                Arc::new(path)
+            })
+            .collect();
     }
 
+    // Units that stay at their goal are padded to the common end, the others only as long as
+    // they must hold it
     fn extend_paths(&mut self) {
         let mut end_time = 0;
         for path in &self.solution {
@@ -80,13 +113,29 @@ impl CBS<'_> {
         }
         for path in self.solution.iter_mut() {
             let idx = path.len() - 1;
-            path[idx].duration.1 = end_time
+            let end = match self.astar.at_goal {
+                AtGoal::Stay => end_time,
+                // Earlier padding may be stale, the tick the goal is fully entered is not
+                AtGoal::Disappear | AtGoal::HoldUntil(_) => {
+                    self.astar.at_goal.until(self.astar.grid.finished(path))
+                }
+            };
+            if path[idx].duration.1 != end {
+                Arc::make_mut(path)[idx].duration.1 = end
+            }
         }
     }
 
+    fn end_time(&self) -> usize {
+        self.solution
+            .iter()
+            .map(|path| path[path.len() - 1].duration.1)
+            .max()
+            .unwrap_or(0)
+    }
+
     fn find_cost(&mut self) {
-        let path = &self.solution[0];
-        self.cost = path[path.len() - 1].duration.1;
+        self.cost = self.astar.objective.cost(&self.astar.grid, &self.solution);
     }
 
     fn to_conflict(state_i: &UnitState, state_j: &UnitState) -> Conflict {
@@ -100,22 +149,55 @@ impl CBS<'_> {
             location: state_j.location,
             duration: state_j.duration,
         };
-        Conflict(cii, cij)
+        Conflict::Collision(cii, cij)
+    }
+
+    // Followers further from their leaders than allowed. Positions after the last tick last
+    // forever, so that counts as a boundary too.
+    fn find_strays(&mut self, state: &[UnitState], time: usize, end_time: usize) {
+        for cohesion in &self.astar.cohesion {
+            if !time.is_multiple_of(cohesion.turn.max(1)) && time != end_time {
+                continue;
+            }
+            let find = |uid: Pair| state.iter().find(|unit| unit.uid == uid && !unit.gone);
+            let (Some(follower), Some(leader)) = (find(cohesion.follower), find(cohesion.leader))
+            else {
+                continue;
+            };
+            if follower.location.distance(leader.location) > cohesion.radius {
+                let info = |unit: &UnitState| ConflictInfo {
+                    uid: unit.uid,
+                    location: unit.location,
+                    duration: Pair(time, time),
+                };
+                self.conflicts.push(Conflict::Stray(
+                    info(follower),
+                    info(leader),
+                    cohesion.radius,
+                ));
+            }
+        }
     }
 
     fn find_conflicts(&mut self) {
         let mut state = Vec::with_capacity(self.solution.len());
-        let end_time = self.cost;
+        let end_time = self.end_time();
         for path in &self.solution {
+            let uid = path[0].location.origin;
             state.push(UnitState {
-                uid: path[0].location.origin,
+                uid,
+                faction: self.astar.faction(uid),
                 path_idx: 0,
                 location: path[0].location,
                 duration: path[0].duration,
+                // Nobody has left their origin yet
+                still: true,
+                gone: false,
             });
         }
         for time in 1..=end_time {
             let mut moved = vec![false; state.len()];
+            let mut settled = vec![false; state.len()];
             for (i, path) in self.solution.iter().enumerate() {
                 let idx = state[i].path_idx;
                 if time > path[idx].duration.1 && idx < path.len() - 1 {
@@ -124,32 +206,54 @@ impl CBS<'_> {
                     state[i].path_idx += 1;
                     moved[i] = true;
                 }
+                // Entering a cell takes its cost in ticks, whatever is left of the stay is waiting
+                let entered = match state[i].path_idx {
+                    0 => state[i].duration.0,
+                    _ => state[i].duration.0 + self.astar.grid.cost(state[i].location),
+                };
+                let still = state[i].path_idx == path.len() - 1 || time >= entered;
+                settled[i] = still && !state[i].still;
+                state[i].still = still;
+                state[i].gone = time > path[path.len() - 1].duration.1;
             }
             // Check for conflicts
             for (i, i_moved) in moved.iter().enumerate() {
                 for (j, j_moved) in moved.iter().enumerate().skip(i + 1) {
                     let intersects = state[i].location.intersects(state[j].location);
-                    let includes_moved = *i_moved || *j_moved;
-                    if intersects && includes_moved {
+                    // Allies can start blocking each other by both stopping to wait where they are
+                    let includes_moved = *i_moved
+                        || *j_moved
+                        || state[i].is_ally(&state[j]) && (settled[i] || settled[j]);
+                    let present = !(state[i].gone || state[j].gone);
+                    let blocks = state[i].blocks(&state[j]);
+                    if intersects && includes_moved && present && blocks {
                         self.conflicts.push(CBS::to_conflict(&state[i], &state[j]));
                     }
                 }
             }
+            self.find_strays(&state, time, end_time);
         }
     }
 
     /// Exploration functions
 
     fn explore_constraint(&self, constraint: Constraint) -> Option<Path> {
-        let mut constraints = self.constraints.clone();
-        constraints.push(constraint);
-        self.astar.astar(constraint.uid, &constraints)
+        self.astar
+            .astar_constrained(constraint.uid(), &self.table.with(constraint))
     }
 
     fn explore_conflict(&self, conflict: Conflict) -> Exploration {
         let constraints = Conflict::constraints(conflict);
-        let path_0 = self.explore_constraint(constraints[0]);
-        let path_1 = self.explore_constraint(constraints[1]);
+        #[cfg(feature = "rayon")]
+        let (path_0, path_1) = rayon::join(
+            || self.explore_constraint(constraints[0]),
+            || self.explore_constraint(constraints[1]),
+        );
+        #[cfg(not(feature = "rayon"))]
+        let (path_0, path_1) = (
+            self.explore_constraint(constraints[0]),
+            self.explore_constraint(constraints[1]),
+        );
         Exploration {
             conflict,
             constraints,
@@ -157,19 +261,21 @@ impl CBS<'_> {
         }
     }
 
+    // Collecting keeps the conflict order, so the result does not depend on the thread count.
     fn explore(&self) -> Vec<Exploration> {
-        let mut explorations = Vec::with_capacity(self.conflicts.len());
-        for conflict in &self.conflicts {
-            let exploration = self.explore_conflict(*conflict);
-            explorations.push(exploration);
-        }
-        explorations
+        #[cfg(feature = "rayon")]
+        let conflicts = self.conflicts.par_iter();
+        #[cfg(not(feature = "rayon"))]
+        let conflicts = self.conflicts.iter();
+        conflicts
+            .map(|conflict| self.explore_conflict(*conflict))
+            .collect()
     }
 
     fn change_path(&mut self, path: Path) {
         for (idx, old_path) in self.solution.iter().enumerate() {
             if path[0].location == old_path[0].location {
-                self.solution[idx] = path;
+                self.solution[idx] = Arc::new(path);
                 break;
             }
         }
@@ -280,6 +386,7 @@ fn greedy_choices(explorations: Vec<Exploration>) -> Vec<Exploration> {
 
 fn update_cbs(mut cbs: CBS, constrait: Constraint, path: Path) -> CBS {
     cbs.constraints.push(constrait);
+    cbs.table.insert(constrait);
     cbs.change_path(path);
     cbs.extend_paths();
     cbs.find_cost();
@@ -306,11 +413,15 @@ fn expand_explorations(cbs: CBS, explorations: Vec<Exploration>) -> Vec<CBS> {
         }
         out = new_out;
     }
-    for node in out.iter_mut() {
+    #[cfg(feature = "rayon")]
+    let nodes = out.par_iter_mut();
+    #[cfg(not(feature = "rayon"))]
+    let nodes = out.iter_mut();
+    nodes.for_each(|node| {
         node.extend_paths();
         node.conflicts = Vec::new();
         node.find_conflicts();
-    }
+    });
     out
 }
 
@@ -320,10 +431,13 @@ fn expand_node(cbs: CBS) -> Vec<CBS> {
     expand_explorations(cbs, explorations)
 }
 
-fn greedy_with_heuristic(cbs: CBS) -> Vec<Path> {
+fn greedy_with_heuristic(cbs: CBS, cancel: &CancelToken) -> Option<(Vec<Path>, usize)> {
     let mut open = BinaryHeap::new();
     open.push(cbs);
     loop {
+        if cancel.is_cancelled() {
+            return None;
+        }
         let node = match open.pop() {
             None => panic!("Exhausted states. Should be impossible."),
             Some(new_node) => new_node,
@@ -331,7 +445,12 @@ fn greedy_with_heuristic(cbs: CBS) -> Vec<Path> {
         let children = expand_node(node.clone());
         for child in children {
             if child.conflicts.is_empty() {
-                return child.solution;
+                let paths = child
+                    .solution
+                    .into_iter()
+                    .map(Arc::unwrap_or_clone)
+                    .collect();
+                return Some((paths, child.cost));
             } else {
                 open.push(child);
             }
@@ -340,6 +459,196 @@ fn greedy_with_heuristic(cbs: CBS) -> Vec<Path> {
 }
 
 pub fn solve_mapf(mapf: &AStar) -> Vec<Path> {
+    solve_mapf_cancellable(mapf, &CancelToken::new()).expect("Solver was cancelled!")
+}
+
+// Returns None if `cancel` fires before a solution is found.
+pub fn solve_mapf_cancellable(mapf: &AStar, cancel: &CancelToken) -> Option<Vec<Path>> {
+    solve_mapf_with_cost_cancellable(mapf, cancel).map(|(paths, _)| paths)
+}
+
+// As solve_mapf(), with the cost of the solution under `mapf.objective`
+pub fn solve_mapf_with_cost(mapf: &AStar) -> (Vec<Path>, usize) {
+    solve_mapf_with_cost_cancellable(mapf, &CancelToken::new()).expect("Solver was cancelled!")
+}
+
+pub fn solve_mapf_with_cost_cancellable(
+    mapf: &AStar,
+    cancel: &CancelToken,
+) -> Option<(Vec<Path>, usize)> {
     let cbs = CBS::init(mapf);
-    greedy_with_heuristic(cbs)
+    greedy_with_heuristic(cbs, cancel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::Grid;
+    use crate::testing;
+    use std::collections::HashMap;
+
+    fn at(path: &Path, time: usize) -> Rect {
+        path.iter()
+            .find(|scored_cell| scored_cell.duration.0 <= time && time <= scored_cell.duration.1)
+            .unwrap_or(&path[path.len() - 1])
+            .location
+    }
+
+    // Furthest apart the first two units get at any tick
+    fn spread(paths: &[Path]) -> usize {
+        let horizon = paths
+            .iter()
+            .map(|path| path[path.len() - 1].duration.1)
+            .max();
+        (0..=horizon.unwrap())
+            .map(|time| at(&paths[1], time).distance(at(&paths[0], time)))
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn leaders_and_followers_move_together() {
+        // One row is slow, so whoever is on the other has to hold back
+        let rows = ["..........", "3333333333", "##########"];
+        for (leader, follower) in [(Pair(0, 0), Pair(0, 1)), (Pair(0, 1), Pair(0, 0))] {
+            let mut astar = AStar::init(
+                vec![leader, follower],
+                vec![Pair(9, 0), Pair(9, 1)],
+                Pair(0, 0),
+                testing::parse(&rows),
+            );
+            let (free, free_cost) = solve_mapf_with_cost(&astar);
+            assert!(spread(&free) > 1);
+            for radius in [1, 2] {
+                astar.cohesion = vec![Cohesion {
+                    leader,
+                    follower,
+                    radius,
+                    turn: 1,
+                }];
+                let (paths, cost) = solve_mapf_with_cost(&astar);
+                assert!(spread(&paths) <= radius);
+                assert!(cost >= free_cost);
+                for path in &paths {
+                    assert!(astar
+                        .destinations
+                        .contains(&path[path.len() - 1].location.origin));
+                }
+            }
+        }
+    }
+
+    // Whether the unit is done entering where it is at `time`, as CBS sees it
+    fn still_at(grid: &Grid<CellInfo>, path: &Path, time: usize) -> bool {
+        let idx = path
+            .iter()
+            .position(|cell| cell.duration.0 <= time && time <= cell.duration.1)
+            .unwrap_or(path.len() - 1);
+        idx == 0
+            || idx == path.len() - 1
+            || time >= path[idx].duration.0 + grid.cost(path[idx].location)
+    }
+
+    #[test]
+    fn allies_pass_through_but_never_wait_together() {
+        let grid = testing::parse(&["........"]);
+        let gate = Obstacle {
+            location: Pair(3, 0).extend(Pair(0, 0)),
+            duration: Pair(0, 8),
+        };
+        let origins = vec![Pair(0, 0), Pair(1, 0)];
+        let mut astar = AStar::init(
+            origins.clone(),
+            vec![Pair(6, 0), Pair(7, 0)],
+            Pair(0, 0),
+            grid,
+        );
+        astar.set_obstacles(vec![gate]);
+        // On their own, both would wait right at the gate
+        for origin in &origins {
+            let path = astar.astar(*origin, &[]).unwrap();
+            assert!(path
+                .iter()
+                .any(|cell| cell.location.origin == Pair(2, 0) && cell.duration.1 == 8));
+        }
+        for factions in [
+            HashMap::new(),
+            HashMap::from([(origins[0], Faction(0)), (origins[1], Faction(0))]),
+        ] {
+            astar.set_factions(factions);
+            let paths = solve_mapf(&astar);
+            let end = paths
+                .iter()
+                .map(|path| path[path.len() - 1].duration.1)
+                .max();
+            for time in 0..=end.unwrap() {
+                let (a, b) = (at(&paths[0], time), at(&paths[1], time));
+                let both_still = still_at(&astar.grid, &paths[0], time)
+                    && still_at(&astar.grid, &paths[1], time);
+                let allied = astar.faction(origins[0]).is_some();
+                assert!(
+                    !a.intersects(b) || allied && !both_still,
+                    "{:?} at {}",
+                    paths,
+                    time
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn units_leaving_are_there_until_done_entering_their_goal() {
+        // Stepping straight onto the dear cell takes ticks 1 to 3, when the other would cross it
+        let grid = testing::parse(&["###.###", "...3..."]);
+        let mut astar = AStar::init(
+            vec![Pair(3, 0), Pair(0, 1)],
+            vec![Pair(3, 1), Pair(6, 1)],
+            Pair(0, 0),
+            grid,
+        );
+        for at_goal in [AtGoal::Disappear, AtGoal::HoldUntil(2)] {
+            astar.at_goal = at_goal;
+            let paths = solve_mapf(&astar);
+            let finished = |path: &Path| astar.grid.finished(path);
+            for path in &paths {
+                assert_eq!(
+                    path[path.len() - 1].duration.1,
+                    at_goal.until(finished(path))
+                );
+            }
+            // Whatever the paths claim, a unit is around until it is done entering its goal
+            let present = |path: &Path, time: usize| {
+                time <= finished(path).max(path[path.len() - 1].duration.1)
+            };
+            for time in 0..=paths.iter().map(finished).max().unwrap() {
+                let (a, b) = (at(&paths[0], time), at(&paths[1], time));
+                let both = present(&paths[0], time) && present(&paths[1], time);
+                assert!(!both || !a.intersects(b), "{:?} at {}", at_goal, time);
+            }
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn thread_count_never_changes_the_solution() {
+        // Sized like the binary's test case, a column of units crossing a sparse map
+        let origins: Vec<Pair> = (0..5).map(|i| Pair(3, 2 + 2 * i)).collect();
+        let destinations: Vec<Pair> = (0..5).map(|i| Pair(15, 3 + 2 * i)).collect();
+        let mut grid = testing::random(8, Pair(100, 75), 0.1, 1);
+        for cell in origins.iter().chain(&destinations) {
+            grid[*cell].blocked = false;
+        }
+        let astar = AStar::init(origins, destinations, Pair(0, 0), grid);
+        let solve = |threads: usize| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| solve_mapf_with_cost(&astar))
+        };
+        let single = solve(1);
+        for threads in [2, 4] {
+            assert_eq!(solve(threads), single, "{} threads", threads);
+        }
+    }
 }
@@ -0,0 +1,368 @@
+use crate::prelude::*;
+use std::collections::HashMap;
+
+// Closed time intervals on one cell, sorted by start with a running max of the ends. Overlap
+// queries take log time as in an interval tree, but this is a sorted Vec rather than a tree:
+// insert is linear, shifting the later intervals and recomputing the running max after them.
+// That stays cheap for the handful of constraints a cell usually collects.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct IntervalSet {
+    intervals: Vec<Pair>,
+    max_end: Vec<usize>,
+}
+
+impl IntervalSet {
+    pub fn insert(&mut self, interval: Pair) {
+        let idx = self
+            .intervals
+            .partition_point(|other| other.0 <= interval.0);
+        self.intervals.insert(idx, interval);
+        self.max_end.truncate(idx);
+        let mut running = idx.checked_sub(1).map_or(0, |prev| self.max_end[prev]);
+        for other in &self.intervals[idx..] {
+            running = running.max(other.1);
+            self.max_end.push(running);
+        }
+    }
+
+    pub fn overlaps(&self, interval: Pair) -> bool {
+        // Only intervals starting no later than we end can overlap
+        let candidates = self
+            .intervals
+            .partition_point(|other| other.0 <= interval.1);
+        candidates > 0 && self.max_end[candidates - 1] >= interval.0
+    }
+
+    // Last time step covered by any interval
+    pub fn last_end(&self) -> Option<usize> {
+        self.max_end.last().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.intervals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+}
+
+// The constraints on a single unit, indexed by every cell they cover
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct UnitConstraints {
+    cells: HashMap<Pair, IntervalSet>,
+    // Regions to stay near as (time, location, radius), sorted by time
+    near: Vec<(usize, Rect, usize)>,
+    // Exact locations to be away from as (time, location), sorted by time
+    leave: Vec<(usize, Rect)>,
+    horizon: usize,
+}
+
+impl UnitConstraints {
+    pub fn new(uid: Pair, constraints: &[Constraint]) -> UnitConstraints {
+        let mut out = UnitConstraints::default();
+        for constraint in constraints {
+            if constraint.uid() == uid {
+                out.insert(*constraint);
+            }
+        }
+        out
+    }
+
+    // Obstacles block every unit alike, so one index serves them all
+    pub fn from_obstacles(obstacles: &[Obstacle]) -> UnitConstraints {
+        let mut out = UnitConstraints::default();
+        for obstacle in obstacles {
+            out.avoid(obstacle.location, obstacle.duration);
+        }
+        out
+    }
+
+    pub fn avoid(&mut self, location: Rect, duration: Pair) {
+        for cell in location.cells() {
+            self.cells.entry(cell).or_default().insert(duration);
+        }
+        // Nothing changes once a block that never clears has started
+        let last_change = if duration.1 == usize::MAX {
+            duration.0
+        } else {
+            duration.1
+        };
+        self.horizon = self.horizon.max(last_change);
+    }
+
+    // Assumes the constraint is on this unit
+    pub fn insert(&mut self, constraint: Constraint) {
+        match constraint {
+            Constraint::Avoid {
+                location, duration, ..
+            } => self.avoid(location, duration),
+            Constraint::Near {
+                location,
+                radius,
+                time,
+                ..
+            } => {
+                let idx = self.near.partition_point(|other| other.0 <= time);
+                self.near.insert(idx, (time, location, radius));
+                self.horizon = self.horizon.max(time);
+            }
+            Constraint::Leave { location, time, .. } => {
+                let idx = self.leave.partition_point(|other| other.0 <= time);
+                self.leave.insert(idx, (time, location));
+                self.horizon = self.horizon.max(time);
+            }
+        }
+    }
+
+    // Last time step at which any constraint is active
+    pub fn horizon(&self) -> usize {
+        self.horizon
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty() && self.near.is_empty() && self.leave.is_empty()
+    }
+
+    // Is `location` near enough to every region it has to be near from `from` to `to`, and
+    // nowhere it has to leave?
+    fn keeps_near(&self, location: Rect, from: usize, to: usize) -> bool {
+        let start = self.near.partition_point(|other| other.0 < from);
+        let near = self.near[start..]
+            .iter()
+            .take_while(|(time, _, _)| *time <= to)
+            .all(|(_, region, radius)| location.distance(*region) <= *radius);
+        let start = self.leave.partition_point(|other| other.0 < from);
+        near && self.leave[start..]
+            .iter()
+            .take_while(|(time, _)| *time <= to)
+            .all(|(_, region)| location != *region)
+    }
+
+    // Checks the intervals on every cell of `location`, without allocating a cell list
+    fn all_cells(&self, location: Rect, check: impl Fn(&IntervalSet) -> bool) -> bool {
+        let max = location.max_coord();
+        for x in location.origin.0..=max.0 {
+            for y in location.origin.1..=max.1 {
+                if let Some(intervals) = self.cells.get(&Pair(x, y)) {
+                    if !check(intervals) {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    // May the unit occupy `location` from arrival to departure?
+    pub fn allows(&self, scored_cell: &ScoredCell) -> bool {
+        let duration = scored_cell.duration;
+        if !self.keeps_near(scored_cell.location, duration.0, duration.1) {
+            return false;
+        }
+        if self.cells.is_empty() {
+            return true;
+        }
+        self.all_cells(scored_cell.location, |intervals| {
+            !intervals.overlaps(scored_cell.duration)
+        })
+    }
+
+    // May the unit stay at `location` from its arrival until `until`?
+    pub fn may_stay(&self, scored_cell: &ScoredCell, until: usize) -> bool {
+        let stay = ScoredCell {
+            duration: Pair(scored_cell.duration.0, until.max(scored_cell.duration.1)),
+            ..*scored_cell
+        };
+        self.allows(&stay)
+    }
+
+    // May the unit stay at `location` from its arrival on, forever?
+    pub fn may_stop(&self, scored_cell: &ScoredCell) -> bool {
+        if !self.keeps_near(scored_cell.location, scored_cell.duration.0, usize::MAX) {
+            return false;
+        }
+        if self.cells.is_empty() {
+            return true;
+        }
+        self.all_cells(scored_cell.location, |intervals| {
+            intervals
+                .last_end()
+                .is_none_or(|end| end < scored_cell.duration.0)
+        })
+    }
+}
+
+// Every unit's constraints, so CBS nodes can hand A* a prebuilt index
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct ConstraintTable {
+    units: HashMap<Pair, UnitConstraints>,
+}
+
+impl ConstraintTable {
+    pub fn new(constraints: &[Constraint]) -> ConstraintTable {
+        let mut out = ConstraintTable::default();
+        for constraint in constraints {
+            out.insert(*constraint);
+        }
+        out
+    }
+
+    pub fn insert(&mut self, constraint: Constraint) {
+        self.units
+            .entry(constraint.uid())
+            .or_default()
+            .insert(constraint)
+    }
+
+    pub fn unit(&self, uid: Pair) -> Option<&UnitConstraints> {
+        self.units.get(&uid)
+    }
+
+    // The unit's constraints plus one more, leaving the table untouched
+    pub fn with(&self, constraint: Constraint) -> UnitConstraints {
+        let mut unit = self.unit(constraint.uid()).cloned().unwrap_or_default();
+        unit.insert(constraint);
+        unit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // The linear scans A* used before constraints were indexed
+    fn linear_allows(scored_cell: &ScoredCell, constraints: &[Constraint]) -> bool {
+        constraints.iter().all(|constraint| match *constraint {
+            Constraint::Avoid {
+                location, duration, ..
+            } => {
+                !(location.intersects(scored_cell.location)
+                    && duration.0 <= scored_cell.duration.1
+                    && scored_cell.duration.0 <= duration.1)
+            }
+            Constraint::Near {
+                location,
+                radius,
+                time,
+                ..
+            } => {
+                !(scored_cell.duration.0 <= time && time <= scored_cell.duration.1)
+                    || location.distance(scored_cell.location) <= radius
+            }
+            Constraint::Leave { location, time, .. } => {
+                !(scored_cell.duration.0 <= time && time <= scored_cell.duration.1)
+                    || location != scored_cell.location
+            }
+        })
+    }
+
+    fn linear_may_stop(scored_cell: &ScoredCell, constraints: &[Constraint]) -> bool {
+        constraints.iter().all(|constraint| match *constraint {
+            Constraint::Avoid {
+                location, duration, ..
+            } => {
+                !(location.intersects(scored_cell.location) && scored_cell.duration.0 <= duration.1)
+            }
+            Constraint::Near {
+                location,
+                radius,
+                time,
+                ..
+            } => time < scored_cell.duration.0 || location.distance(scored_cell.location) <= radius,
+            Constraint::Leave { location, time, .. } => {
+                time < scored_cell.duration.0 || location != scored_cell.location
+            }
+        })
+    }
+
+    fn random_rect(rng: &mut impl Rng, max_origin: usize, max_extent: usize) -> Rect {
+        let origin = Pair(rng.gen_range(0..=max_origin), rng.gen_range(0..=max_origin));
+        let extent = Pair(rng.gen_range(0..=max_extent), rng.gen_range(0..=max_extent));
+        origin.extend(extent)
+    }
+
+    fn random_duration(rng: &mut impl Rng, max_time: usize) -> Pair {
+        let start = rng.gen_range(0..=max_time);
+        Pair(start, start + rng.gen_range(0..=max_time / 4))
+    }
+
+    #[test]
+    fn table_agrees_with_linear_scan() {
+        let mut rng = StdRng::seed_from_u64(30);
+        let uid = Pair(0, 0);
+        for _ in 0..1000 {
+            let mut constraints = Vec::new();
+            for _ in 0..rng.gen_range(0..20) {
+                let owner = if rng.gen_bool(0.8) { uid } else { Pair(1, 1) };
+                let location = random_rect(&mut rng, 10, 2);
+                let duration = random_duration(&mut rng, 40);
+                let kind = rng.gen_range(0..10);
+                constraints.push(if kind < 2 {
+                    Constraint::Near {
+                        uid: owner,
+                        location,
+                        radius: rng.gen_range(0..=6),
+                        time: duration.0,
+                    }
+                } else if kind < 3 {
+                    Constraint::Leave {
+                        uid: owner,
+                        // Small, so the exact location comes up now and then
+                        location: random_rect(&mut rng, 3, 1),
+                        time: duration.0,
+                    }
+                } else {
+                    Constraint::Avoid {
+                        uid: owner,
+                        location,
+                        duration,
+                    }
+                });
+            }
+            let mine: Vec<Constraint> = constraints
+                .iter()
+                .filter(|constraint| constraint.uid() == uid)
+                .copied()
+                .collect();
+            let table = UnitConstraints::new(uid, &constraints);
+            for _ in 0..200 {
+                let scored_cell = ScoredCell {
+                    location: random_rect(&mut rng, 10, 1),
+                    duration: random_duration(&mut rng, 40),
+                    cost: 0,
+                    leg: 0,
+                };
+                assert_eq!(
+                    table.allows(&scored_cell),
+                    linear_allows(&scored_cell, &mine),
+                    "allows {:?} under {:?}",
+                    scored_cell,
+                    mine
+                );
+                assert_eq!(
+                    table.may_stop(&scored_cell),
+                    linear_may_stop(&scored_cell, &mine),
+                    "may_stop {:?} under {:?}",
+                    scored_cell,
+                    mine
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn interval_set_overlaps() {
+        let mut set = IntervalSet::default();
+        set.insert(Pair(5, 8));
+        set.insert(Pair(0, 20));
+        set.insert(Pair(30, 31));
+        assert!(!set.overlaps(Pair(21, 21)));
+        assert!(set.overlaps(Pair(20, 22)));
+        assert!(set.overlaps(Pair(25, 30)));
+        assert!(!set.overlaps(Pair(32, 40)));
+        assert_eq!(set.last_end(), Some(31));
+    }
+}
@@ -0,0 +1,356 @@
+use crate::grid::Grid;
+use crate::prelude::*;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+// Primary then secondary priority of a queued origin, smallest first
+type Key = (usize, usize);
+
+// D* Lite for one unit with a fixed goal. The search runs backwards from the goal, so the unit can
+// move and terrain can change while most of the cost-to-goal tree is kept; only origins whose cost
+// actually changed are expanded again. Report every change to the grid through `update`. If the
+// grid was edited after the last report, its version gives that away and the next route is
+// planned from scratch. A report itself is trusted to name every cell it changed.
+pub struct DStarLite {
+    extent: Pair,
+    start: Pair,
+    goal: Pair,
+    // Added to every key when the start moves, so queued keys stay valid lower bounds
+    offset: usize,
+    // Cost of moving onto the cheapest terrain, guides the search towards the start
+    min_step: usize,
+    // Cost to the goal, and its one step lookahead
+    g: Grid<usize>,
+    rhs: Grid<usize>,
+    open: BinaryHeap<Reverse<(Key, Pair)>>,
+    // Current key of every origin in `open`, older heap entries are skipped
+    queued: HashMap<Pair, Key>,
+    grid: u64,
+    version: u64,
+    expansions: usize,
+}
+
+impl DStarLite {
+    pub fn new(grid: &Grid<CellInfo>, extent: Pair, start: Pair, goal: Pair) -> DStarLite {
+        let mut planner = DStarLite {
+            extent,
+            start,
+            goal,
+            offset: 0,
+            min_step: 0,
+            g: Grid::init(grid.extent(), usize::MAX),
+            rhs: Grid::init(grid.extent(), usize::MAX),
+            open: BinaryHeap::new(),
+            queued: HashMap::new(),
+            grid: grid.id(),
+            version: grid.version(),
+            expansions: 0,
+        };
+        planner.reset(grid);
+        planner
+    }
+
+    pub fn start(&self) -> Pair {
+        self.start
+    }
+
+    pub fn goal(&self) -> Pair {
+        self.goal
+    }
+
+    // Origins expanded since the planner was made, to see how much a repair saved
+    pub fn expansions(&self) -> usize {
+        self.expansions
+    }
+
+    // Forgets the search tree, the next route is planned from scratch
+    fn reset(&mut self, grid: &Grid<CellInfo>) {
+        self.min_step = grid.min_step(self.extent);
+        self.offset = 0;
+        self.g = Grid::init(grid.extent(), usize::MAX);
+        self.rhs = Grid::init(grid.extent(), usize::MAX);
+        self.open.clear();
+        self.queued.clear();
+        self.grid = grid.id();
+        self.version = grid.version();
+        if self.is_open(grid, self.goal) {
+            self.rhs[self.goal] = 0;
+            let key = self.key(self.goal);
+            self.push(self.goal, key);
+        }
+    }
+
+    fn is_open(&self, grid: &Grid<CellInfo>, origin: Pair) -> bool {
+        let location = origin.extend(self.extent);
+        grid.in_bounds(location) && grid.is_clear(location)
+    }
+
+    fn g(&self, origin: Pair) -> usize {
+        self.g[origin]
+    }
+
+    fn rhs(&self, origin: Pair) -> usize {
+        self.rhs[origin]
+    }
+
+    fn estimate(&self, from: Pair, to: Pair) -> usize {
+        (from.0.abs_diff(to.0) + from.1.abs_diff(to.1)) * self.min_step
+    }
+
+    fn key(&self, origin: Pair) -> Key {
+        let best = self.g(origin).min(self.rhs(origin));
+        let estimate = self.estimate(self.start, origin) + self.offset;
+        (best.saturating_add(estimate), best)
+    }
+
+    fn push(&mut self, origin: Pair, key: Key) {
+        self.queued.insert(origin, key);
+        self.open.push(Reverse((key, origin)));
+    }
+
+    // Drops stale heap entries, leaving the smallest live one on top
+    fn top(&mut self) -> Option<(Key, Pair)> {
+        while let Some(Reverse((key, origin))) = self.open.peek().copied() {
+            if self.queued.get(&origin) == Some(&key) {
+                return Some((key, origin));
+            }
+            self.open.pop();
+        }
+        None
+    }
+
+    // Open origins one step from `origin`, with the cost of stepping onto them
+    fn successors(&self, grid: &Grid<CellInfo>, origin: Pair) -> Vec<(Pair, usize)> {
+        grid.neighbors(origin.extend(self.extent))
+            .into_iter()
+            .map(|next| (next.origin, grid.cost(next)))
+            .collect()
+    }
+
+    fn update_vertex(&mut self, grid: &Grid<CellInfo>, origin: Pair) {
+        let mut best = usize::MAX;
+        if origin == self.goal && self.is_open(grid, origin) {
+            best = 0;
+        } else if self.is_open(grid, origin) {
+            for (next, cost) in self.successors(grid, origin) {
+                best = best.min(self.g(next).saturating_add(cost));
+            }
+        }
+        self.rhs[origin] = best;
+        self.queued.remove(&origin);
+        if self.g(origin) != self.rhs(origin) {
+            let key = self.key(origin);
+            self.push(origin, key);
+        }
+    }
+
+    fn compute(&mut self, grid: &Grid<CellInfo>) {
+        while let Some((old_key, origin)) = self.top() {
+            let start_key = self.key(self.start);
+            if old_key >= start_key && self.rhs(self.start) == self.g(self.start) {
+                break;
+            }
+            let new_key = self.key(origin);
+            if old_key < new_key {
+                self.push(origin, new_key);
+                continue;
+            }
+            self.queued.remove(&origin);
+            self.expansions += 1;
+            let rhs = self.rhs(origin);
+            if self.g(origin) > rhs {
+                self.g[origin] = rhs;
+            } else {
+                self.g[origin] = usize::MAX;
+                self.update_vertex(grid, origin);
+            }
+            // Origins that can step onto this one, movement is symmetric
+            for (prev, _) in self.successors(grid, origin) {
+                self.update_vertex(grid, prev);
+            }
+        }
+    }
+
+    // Call after changing the cells in `changed`, with every edit since the last call
+    pub fn update(&mut self, grid: &Grid<CellInfo>, changed: &[Rect]) {
+        // A rect reaching off the grid is a bad report, so it isn't trusted with the tree.
+        // Cheaper terrain would make the estimates overshoot.
+        let valid = changed.iter().all(|rect| grid.in_bounds(*rect));
+        let cheaper = || {
+            changed
+                .iter()
+                .flat_map(|rect| rect.cells())
+                .any(|cell| grid[cell].cost * self.area() < self.min_step)
+        };
+        if self.grid != grid.id() || !valid || cheaper() {
+            self.reset(grid);
+            return;
+        }
+        for rect in changed {
+            self.repair(grid, *rect);
+        }
+        self.version = grid.version();
+    }
+
+    // Requeues the origins whose cost to the goal may go through `changed`
+    fn repair(&mut self, grid: &Grid<CellInfo>, changed: Rect) {
+        let low = Pair(
+            changed.origin.0.saturating_sub(self.extent.0),
+            changed.origin.1.saturating_sub(self.extent.1),
+        );
+        let high = changed.max_coord();
+        for x in low.0..=high.0 {
+            for y in low.1..=high.1 {
+                let origin = Pair(x, y);
+                if !grid.in_bounds(origin.extend(self.extent)) {
+                    continue;
+                }
+                // Stepping onto the changed origin now costs something else
+                self.update_vertex(grid, origin);
+                for (prev, _) in self.successors(grid, origin) {
+                    self.update_vertex(grid, prev);
+                }
+            }
+        }
+    }
+
+    fn area(&self) -> usize {
+        (self.extent.0 + 1) * (self.extent.1 + 1)
+    }
+
+    // The unit moved, the tree is kept
+    pub fn move_to(&mut self, start: Pair) {
+        self.offset += self.estimate(self.start, start);
+        self.start = start;
+    }
+
+    // Cost and every origin on the way, the start first and the goal last
+    pub fn route(&mut self, grid: &Grid<CellInfo>) -> Option<(usize, Vec<Pair>)> {
+        if self.grid != grid.id() || self.version != grid.version() {
+            self.reset(grid);
+        }
+        if !self.is_open(grid, self.start) {
+            return None;
+        }
+        self.compute(grid);
+        let cost = self.g(self.start);
+        if cost == usize::MAX {
+            return None;
+        }
+        Some((cost, self.descend(grid)))
+    }
+
+    // Follows the tree from the start to the goal, only along steps a cheapest route can take.
+    // Zero-cost cells tie their neighbours, so it goes breadth first and remembers where it came
+    // from, as reach::descend does.
+    fn descend(&self, grid: &Grid<CellInfo>) -> Vec<Pair> {
+        let mut prev = HashMap::from([(self.start, self.start)]);
+        let mut open = VecDeque::from([self.start]);
+        while let Some(current) = open.pop_front() {
+            if current == self.goal {
+                let mut route = vec![current];
+                let mut origin = current;
+                while origin != self.start {
+                    origin = prev[&origin];
+                    route.push(origin);
+                }
+                route.reverse();
+                return route;
+            }
+            for (next, cost) in self.successors(grid, current) {
+                if !prev.contains_key(&next) && self.g(next).saturating_add(cost) == self.g(current)
+                {
+                    prev.insert(next, current);
+                    open.push_back(next);
+                }
+            }
+        }
+        panic!("A finite cost to the goal has a next step!")
+    }
+
+    pub fn path(&mut self, grid: &Grid<CellInfo>) -> Option<Path> {
+        let (_, route) = self.route(grid)?;
+        Some(grid.timed_route(&route, self.extent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn check_route(dstar: &mut DStarLite, grid: &Grid<CellInfo>) {
+        let exact = grid.djikstra(dstar.goal().extend(Pair(0, 0)))[dstar.start()];
+        match dstar.route(grid) {
+            None => assert_eq!(exact, usize::MAX),
+            Some((cost, route)) => {
+                let locations: Vec<Rect> = route
+                    .iter()
+                    .map(|origin| origin.extend(Pair(0, 0)))
+                    .collect();
+                assert_eq!(route[0], dstar.start());
+                assert_eq!(route[route.len() - 1], dstar.goal());
+                assert_eq!(testing::walk_cost(grid, &locations), cost);
+                assert_eq!(cost, exact);
+            }
+        }
+    }
+
+    #[test]
+    fn replans_match_djikstra_after_reported_edits() {
+        let mut rng = StdRng::seed_from_u64(36);
+        let mut grid = testing::random(36, Pair(19, 14), 0.2, 3);
+        grid.set_blocked(Pair(0, 0).extend(Pair(0, 0)), false);
+        grid.set_blocked(Pair(19, 14).extend(Pair(0, 0)), false);
+        let mut dstar = DStarLite::new(&grid, Pair(0, 0), Pair(0, 0), Pair(19, 14));
+        check_route(&mut dstar, &grid);
+        for _ in 0..40 {
+            let changed = Pair(rng.gen_range(1..17), rng.gen_range(1..12)).extend(Pair(1, 1));
+            if rng.gen_bool(0.5) {
+                grid.set_blocked(changed, rng.gen_bool(0.5));
+            } else {
+                grid.set_cost(changed, rng.gen_range(0..=3));
+            }
+            dstar.update(&grid, &[changed]);
+            check_route(&mut dstar, &grid);
+        }
+    }
+
+    #[test]
+    fn zero_cost_cells_are_routed_across() {
+        let mut grid = testing::parse(&["0000", "1111", "1001"]);
+        let mut dstar = DStarLite::new(&grid, Pair(0, 0), Pair(0, 0), Pair(3, 0));
+        check_route(&mut dstar, &grid);
+        dstar.move_to(Pair(0, 2));
+        check_route(&mut dstar, &grid);
+        let changed = Pair(1, 1).extend(Pair(1, 0));
+        grid.set_cost(changed, 0);
+        dstar.update(&grid, &[changed]);
+        check_route(&mut dstar, &grid);
+    }
+
+    #[test]
+    fn unreported_edits_force_a_replan() {
+        let mut grid = testing::maze();
+        let mut dstar = DStarLite::new(&grid, Pair(0, 0), Pair(0, 0), Pair(11, 8));
+        check_route(&mut dstar, &grid);
+        let reported = Pair(5, 4).extend(Pair(0, 0));
+        let missed = Pair(11, 5).extend(Pair(0, 0));
+        grid.set_blocked(reported, true);
+        dstar.update(&grid, &[reported]);
+        // Edited after the report
+        grid.set_blocked(missed, true);
+        check_route(&mut dstar, &grid);
+        // Both reported at once
+        grid.set_blocked(reported, false);
+        grid.set_blocked(missed, false);
+        dstar.update(&grid, &[reported, missed]);
+        check_route(&mut dstar, &grid);
+        // Reaching off the grid, so not trusted
+        grid.set_blocked(missed, true);
+        dstar.update(&grid, &[Pair(11, 8).extend(Pair(1, 0))]);
+        check_route(&mut dstar, &grid);
+    }
+}
@@ -0,0 +1,621 @@
+use crate::grid::Grid;
+use crate::prelude::*;
+use std::collections::HashMap;
+use std::ops;
+
+// Signed offset of a slot from the formation's anchor
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Offset(pub isize, pub isize);
+
+impl ops::Add<Offset> for Offset {
+    type Output = Offset;
+    fn add(self, rhs: Offset) -> Self::Output {
+        Offset(self.0 + rhs.0, self.1 + rhs.1)
+    }
+}
+
+impl ops::Sub<Offset> for Offset {
+    type Output = Offset;
+    fn sub(self, rhs: Offset) -> Self::Output {
+        Offset(self.0 - rhs.0, self.1 - rhs.1)
+    }
+}
+
+impl ops::Mul<isize> for Offset {
+    type Output = Offset;
+    fn mul(self, rhs: isize) -> Self::Output {
+        Offset(self.0 * rhs, self.1 * rhs)
+    }
+}
+
+impl From<Pair> for Offset {
+    fn from(value: Pair) -> Self {
+        Offset(value.0 as isize, value.1 as isize)
+    }
+}
+
+impl Offset {
+    // Which square ring around the anchor we are on
+    pub fn ring(self) -> usize {
+        self.0.unsigned_abs().max(self.1.unsigned_abs())
+    }
+
+    // Moves `n` tiles counterclockwise around our ring, which has 8 * ring tiles
+    pub fn shift(self, n: isize) -> Offset {
+        let r = self.ring() as isize;
+        if r == 0 {
+            return self;
+        }
+        let mut tile = self;
+        for _ in 0..n.rem_euclid(8 * r) {
+            tile = if tile.0 == r && tile.1 != -r {
+                tile + Offset(0, -1)
+            } else if tile.1 == r && tile.0 != r {
+                tile + Offset(1, 0)
+            } else if tile.0 == -r && tile.1 != r {
+                tile + Offset(0, 1)
+            } else {
+                tile + Offset(-1, 0)
+            };
+        }
+        tile
+    }
+
+    // Clamped onto the grid, `max` being the largest valid origin
+    pub fn clamp(self, max: Pair) -> Pair {
+        Pair(
+            self.0.clamp(0, max.0 as isize) as usize,
+            self.1.clamp(0, max.1 as isize) as usize,
+        )
+    }
+}
+
+// Eighth turns counterclockwise that point +x towards `direction`
+pub fn heading(direction: Offset) -> usize {
+    if direction == Offset(0, 0) {
+        return 0;
+    }
+    // y grows downwards
+    let angle = (-direction.1 as f64).atan2(direction.0 as f64);
+    let eighths = (angle / std::f64::consts::FRAC_PI_4).round() as isize;
+    eighths.rem_euclid(8) as usize
+}
+
+// Ring `radius` counterclockwise, balanced between the sides so a partial ring stays symmetric
+pub fn square_ring(radius: usize) -> Vec<Offset> {
+    if radius == 0 {
+        return vec![Offset(0, 0)];
+    }
+    let r = radius as isize;
+    let mut tiles = Vec::with_capacity(8 * radius);
+    for n in 0..2 * r {
+        for cardinal in [Offset(1, 0), Offset(0, -1), Offset(-1, 0), Offset(0, 1)] {
+            let shift = if n % 2 == 0 { -n / 2 } else { (n + 1) / 2 };
+            tiles.push((cardinal * r).shift(shift));
+        }
+    }
+    tiles
+}
+
+fn column_tile(depth: usize, n: usize) -> Offset {
+    let depth = depth.max(1);
+    let row = (n / depth) as isize;
+    let y = (row + 1) / 2;
+    Offset((n % depth) as isize, if row % 2 == 0 { -y } else { y })
+}
+
+fn wedge_tile(n: usize) -> Offset {
+    let arm = n.div_ceil(2) as isize;
+    Offset(arm, if n.is_multiple_of(2) { -arm } else { arm })
+}
+
+// Shapes face -x, with the anchor in front and the rest trailing behind it along +x
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Shape {
+    // `depth` deep along +x, rows alternating to either side of the anchor
+    Column { depth: usize },
+    Line,
+    // The anchor at the tip, arms trailing behind
+    Wedge,
+    // The ring at `radius` only, as the GDScript Square region. Use a Mask for a filled square.
+    Square { radius: usize },
+    Disk { radius: usize },
+    // Slots in the order given
+    Mask(Vec<Offset>),
+}
+
+impl Shape {
+    // Number of slots, none for shapes that grow with the number of units
+    pub fn len(&self) -> Option<usize> {
+        match self {
+            Shape::Column { .. } | Shape::Line | Shape::Wedge => None,
+            Shape::Square { .. } | Shape::Disk { .. } | Shape::Mask(_) => {
+                Some(self.base_tiles(usize::MAX).len())
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    // The first `count` slots, before any transformation
+    fn base_tiles(&self, count: usize) -> Vec<Offset> {
+        match self {
+            Shape::Column { depth } => (0..count).map(|n| column_tile(*depth, n)).collect(),
+            Shape::Line => (0..count).map(|n| column_tile(1, n)).collect(),
+            Shape::Wedge => (0..count).map(wedge_tile).collect(),
+            Shape::Square { radius } => square_ring(*radius).into_iter().take(count).collect(),
+            Shape::Disk { radius } => {
+                let limit = (radius * radius) as isize;
+                (0..=*radius)
+                    .flat_map(square_ring)
+                    .filter(|tile| tile.0 * tile.0 + tile.1 * tile.1 <= limit)
+                    .take(count)
+                    .collect()
+            }
+            Shape::Mask(tiles) => tiles.iter().take(count).copied().collect(),
+        }
+    }
+}
+
+// Where a formation ended up, as chosen by `Formation::fit`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Placement {
+    pub anchor: Pair,
+    pub rotation: usize,
+    // Origin for each unit, in the order the units were given
+    pub destinations: Vec<Pair>,
+    // Slots that didn't fit and were moved to the nearest free origin instead
+    pub displaced: usize,
+    // Cost of every unit walking to its destination
+    pub travel: usize,
+}
+
+impl Placement {
+    // Best first: fewest displaced slots, then least travel, then fewest eighth turns away from
+    // the formation's rotation, then closest to where we were asked to place it
+    fn rank(&self, rotation: usize, anchor: Pair) -> (usize, usize, usize, usize) {
+        let turns = (self.rotation + 8 - rotation % 8) % 8;
+        let distance = self
+            .anchor
+            .0
+            .abs_diff(anchor.0)
+            .max(self.anchor.1.abs_diff(anchor.1));
+        (self.displaced, self.travel, turns.min(8 - turns), distance)
+    }
+}
+
+// A shape with its transformation: mirrored, then rotated, then spread out
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Formation {
+    pub shape: Shape,
+    // Distance between neighbouring slots, 0 and 1 both leave them touching
+    pub spread: usize,
+    // Flips the shape across the x axis
+    pub mirror: bool,
+    // Eighth turns counterclockwise
+    pub rotation: usize,
+}
+
+impl Formation {
+    pub fn new(shape: Shape) -> Formation {
+        Formation {
+            shape,
+            spread: 1,
+            mirror: false,
+            rotation: 0,
+        }
+    }
+
+    // Rotated so the front points along `direction`
+    pub fn facing(mut self, direction: Offset) -> Formation {
+        self.rotation = heading(direction * -1);
+        self
+    }
+
+    pub fn transform(&self, tile: Offset) -> Offset {
+        let mut tile = tile;
+        if self.mirror {
+            tile.1 = -tile.1;
+        }
+        let steps = (self.rotation % 8) * tile.ring();
+        tile = tile.shift(steps as isize);
+        tile * self.spread.max(1) as isize
+    }
+
+    // Offsets of the first `count` slots, fewer if the shape runs out
+    pub fn slots(&self, count: usize) -> Vec<Offset> {
+        self.shape
+            .base_tiles(count)
+            .into_iter()
+            .map(|tile| self.transform(tile))
+            .collect()
+    }
+
+    // The slots around `anchor` as origins for units with `unit_extent`, clamped onto the grid.
+    // Clamping can put several slots on the same origin.
+    pub fn place(
+        &self,
+        anchor: Pair,
+        count: usize,
+        grid: &Grid<CellInfo>,
+        unit_extent: Pair,
+    ) -> Vec<Pair> {
+        let max = grid.effective_extent(unit_extent);
+        let anchor = Offset::from(anchor);
+        self.slots(count)
+            .into_iter()
+            .map(|slot| (anchor + slot).clamp(max))
+            .collect()
+    }
+
+    // Places the formation near `near` for `units`, the leader first, trying every rotation and
+    // every anchor within `radius`. Unit i takes slot i, or the nearest free origin if that slot
+    // is off the grid, blocked, taken or out of the unit's reach. Clear the units' own cells
+    // first, they will have moved out of the way.
+    pub fn fit(
+        &self,
+        near: Pair,
+        units: &[Rect],
+        grid: &Grid<CellInfo>,
+        radius: usize,
+    ) -> Option<Placement> {
+        let fields: Vec<Grid<usize>> = units.iter().map(|unit| grid.djikstra_from(*unit)).collect();
+        let mut best: Option<Placement> = None;
+        for rotation in 0..8 {
+            let formation = Formation {
+                rotation,
+                ..self.clone()
+            };
+            let slots = formation.slots(units.len());
+            for ring in 0..=radius {
+                for step in square_ring(ring) {
+                    let anchor = Offset::from(near) + step;
+                    if anchor.0 < 0 || anchor.1 < 0 {
+                        continue;
+                    }
+                    let anchor = Pair(anchor.0 as usize, anchor.1 as usize);
+                    if !grid.in_bounds(anchor.extend(Pair(0, 0))) {
+                        continue;
+                    }
+                    let Some(placement) =
+                        Formation::try_place(anchor, rotation, &slots, units, &fields, grid)
+                    else {
+                        continue;
+                    };
+                    let better = best.as_ref().is_none_or(|best| {
+                        placement.rank(self.rotation, near) < best.rank(self.rotation, near)
+                    });
+                    if better {
+                        best = Some(placement);
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    fn try_place(
+        anchor: Pair,
+        rotation: usize,
+        slots: &[Offset],
+        units: &[Rect],
+        fields: &[Grid<usize>],
+        grid: &Grid<CellInfo>,
+    ) -> Option<Placement> {
+        let mut placement = Placement {
+            anchor,
+            rotation,
+            destinations: Vec::with_capacity(units.len()),
+            displaced: 0,
+            travel: 0,
+        };
+        let mut taken: Vec<Rect> = Vec::with_capacity(units.len());
+        let search_rings = grid.extent().0.max(grid.extent().1);
+        for (idx, unit) in units.iter().enumerate() {
+            let max = grid.effective_extent(unit.extent);
+            let fits = |origin: Pair| {
+                let location = origin.extend(unit.extent);
+                grid.in_bounds(location)
+                    && grid.is_clear(location)
+                    && fields[idx][origin] < usize::MAX
+                    && !taken.iter().any(|other| other.intersects(location))
+            };
+            // Shapes that run out of slots send the rest to the anchor, to be displaced from there
+            let ideal = Offset::from(anchor) + slots.get(idx).copied().unwrap_or_default();
+            let in_range = ideal.0 >= 0
+                && ideal.1 >= 0
+                && ideal.0 as usize <= max.0
+                && ideal.1 as usize <= max.1;
+            let destination = if in_range && fits(ideal.clamp(max)) {
+                ideal.clamp(max)
+            } else {
+                placement.displaced += 1;
+                let center = Offset::from(ideal.clamp(max));
+                (0..=search_rings)
+                    .flat_map(square_ring)
+                    .map(|step| center + step)
+                    .filter(|origin| origin.0 >= 0 && origin.1 >= 0)
+                    .map(|origin| Pair(origin.0 as usize, origin.1 as usize))
+                    .find(|origin| fits(*origin))?
+            };
+            placement.travel += fields[idx][destination];
+            placement.destinations.push(destination);
+            taken.push(destination.extend(unit.extent));
+        }
+        Some(placement)
+    }
+}
+
+// Soft cost keeping followers at their offset from the leader while marching. The leader's path
+// is the reference: a follower pays `weight` for every tick and every cell it is out of place, so
+// it squeezes through a corridor if it must and falls back into place afterwards.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FormationKeeping {
+    pub leader: Pair,
+    // Where each follower should be relative to the leader, by uid. These stay in the frame the
+    // formation started in, they don't turn with the leader's heading.
+    pub offsets: HashMap<Pair, Offset>,
+    pub weight: usize,
+    pub reference: Path,
+}
+
+impl FormationKeeping {
+    pub fn new(
+        leader: Pair,
+        offsets: HashMap<Pair, Offset>,
+        weight: usize,
+        reference: Path,
+    ) -> FormationKeeping {
+        FormationKeeping {
+            leader,
+            offsets,
+            weight,
+            reference,
+        }
+    }
+
+    // Offsets between the formation's slots, `units` in slot order with the leader first
+    pub fn offsets(formation: &Formation, units: &[Pair]) -> HashMap<Pair, Offset> {
+        let slots = formation.slots(units.len());
+        let Some(lead) = slots.first().copied() else {
+            return HashMap::new();
+        };
+        units
+            .iter()
+            .zip(&slots)
+            .skip(1)
+            .map(|(uid, slot)| (*uid, *slot - lead))
+            .collect()
+    }
+
+    pub fn is_follower(&self, uid: Pair) -> bool {
+        self.offsets.contains_key(&uid)
+    }
+
+    // When the leader stops, after which nobody has a reason to move for the formation's sake
+    pub fn horizon(&self) -> usize {
+        self.reference.last().map_or(0, |last| last.duration.1)
+    }
+
+    fn leader_at(&self, time: usize) -> Option<Pair> {
+        let idx = self
+            .reference
+            .partition_point(|scored_cell| scored_cell.duration.0 <= time);
+        self.reference
+            .get(idx.checked_sub(1)?)
+            .map(|scored_cell| scored_cell.location.origin)
+    }
+
+    // Cost of the follower `uid` standing at `origin` from arrival to departure
+    pub fn penalty(&self, uid: Pair, origin: Pair, duration: Pair) -> usize {
+        let Some(offset) = self.offsets.get(&uid) else {
+            return 0;
+        };
+        let origin = Offset::from(origin);
+        let mut total = 0;
+        for time in duration.0..=duration.1 {
+            let Some(leader) = self.leader_at(time) else {
+                continue;
+            };
+            let station = Offset::from(leader) + *offset;
+            let deviation = origin.0.abs_diff(station.0) + origin.1.abs_diff(station.1);
+            total += deviation * self.weight;
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::astar::AStar;
+    use crate::testing;
+    use std::collections::HashSet;
+
+    #[test]
+    fn regions_match_the_gdscript_shapes() {
+        assert_eq!(Shape::Square { radius: 0 }.len(), Some(1));
+        for radius in 1..4 {
+            let square = Formation::new(Shape::Square { radius }).slots(usize::MAX);
+            assert_eq!(square.len(), 8 * radius);
+            assert!(square.iter().all(|tile| tile.ring() == radius));
+            assert_eq!(square.iter().collect::<HashSet<_>>().len(), square.len());
+            let disk = Formation::new(Shape::Disk { radius }).slots(usize::MAX);
+            let r = radius as isize;
+            let inside = (-r..=r)
+                .flat_map(|x| (-r..=r).map(move |y| Offset(x, y)))
+                .filter(|tile| tile.0 * tile.0 + tile.1 * tile.1 <= r * r)
+                .count();
+            assert_eq!(disk.len(), inside);
+            assert_eq!(disk.iter().collect::<HashSet<_>>().len(), inside);
+        }
+    }
+
+    #[test]
+    fn placed_slots_work_as_destinations() {
+        let grid = testing::open(Pair(11, 8));
+        let origins = vec![Pair(0, 0), Pair(1, 0), Pair(0, 1), Pair(1, 1), Pair(2, 0)];
+        for shape in [Shape::Line, Shape::Wedge, Shape::Disk { radius: 1 }] {
+            let formation = Formation::new(shape).facing(Offset(1, 0));
+            let slots = formation.place(Pair(8, 4), origins.len(), &grid, Pair(0, 0));
+            assert_eq!(slots.iter().collect::<HashSet<_>>().len(), origins.len());
+            let targets: Vec<Rect> = slots.iter().map(|slot| slot.extend(Pair(0, 0))).collect();
+            let exact = grid.djikstra_to_any(&targets);
+            let astar = AStar::init(
+                origins.clone(),
+                slots.clone(),
+                Pair(0, 0),
+                testing::open(Pair(11, 8)),
+            );
+            for origin in &origins {
+                let path = astar.astar(*origin, &[]).unwrap();
+                let last = path[path.len() - 1];
+                assert!(slots.contains(&last.location.origin));
+                assert_eq!(last.duration.0, exact[*origin]);
+            }
+        }
+    }
+
+    // Travel is what Djikstra charges each unit to its destination
+    fn check_placement(placement: &Placement, units: &[Rect], grid: &Grid<CellInfo>) {
+        let mut travel = 0;
+        for (unit, destination) in units.iter().zip(&placement.destinations) {
+            let location = destination.extend(unit.extent);
+            assert!(grid.in_bounds(location) && grid.is_clear(location));
+            travel += grid.djikstra_from(*unit)[*destination];
+        }
+        assert_eq!(placement.travel, travel);
+        let taken: HashSet<&Pair> = placement.destinations.iter().collect();
+        assert_eq!(taken.len(), units.len());
+    }
+
+    #[test]
+    fn fits_around_walls_near_the_click() {
+        let grid = testing::parse(&[
+            "............",
+            "............",
+            "......#.....",
+            "......#.....",
+            "......#.....",
+            "............",
+        ]);
+        let units: Vec<Rect> = [Pair(0, 0), Pair(0, 1), Pair(0, 2)]
+            .iter()
+            .map(|origin| origin.extend(Pair(0, 0)))
+            .collect();
+        let formation = Formation::new(Shape::Line).facing(Offset(1, 0));
+        // Anywhere within the radius, the line fits without displacing anyone
+        for near in [Pair(3, 2), Pair(6, 3)] {
+            let placement = formation.fit(near, &units, &grid, 2).unwrap();
+            check_placement(&placement, &units, &grid);
+            assert_eq!(placement.displaced, 0);
+            assert!(
+                placement
+                    .anchor
+                    .0
+                    .abs_diff(near.0)
+                    .max(placement.anchor.1.abs_diff(near.1))
+                    <= 2
+            );
+        }
+        // A radius of zero leaves the anchor on the wall, so a unit is displaced
+        let placement = formation.fit(Pair(6, 3), &units, &grid, 0).unwrap();
+        check_placement(&placement, &units, &grid);
+        assert_eq!((placement.anchor, placement.displaced), (Pair(6, 3), 1));
+    }
+
+    #[test]
+    fn keeping_costs_nothing_in_place_and_weight_zero_is_plain_astar() {
+        let grid = testing::parse(&[".........", ".........", "...###...", "........."]);
+        let leader = Pair(0, 0);
+        let follower = Pair(0, 1);
+        let origins = vec![leader, follower];
+        let destinations = vec![Pair(8, 0), Pair(8, 1)];
+        let plain = AStar::init(origins.clone(), destinations.clone(), Pair(0, 0), grid);
+        let reference = plain.astar(leader, &[]).unwrap();
+        let plain_path = plain.astar(follower, &[]).unwrap();
+        let offsets = HashMap::from([(follower, Offset(0, 1))]);
+        for weight in [0, 3] {
+            let mut keeping = AStar::init(
+                origins.clone(),
+                destinations.clone(),
+                Pair(0, 0),
+                testing::parse(&[".........", ".........", "...###...", "........."]),
+            );
+            let formation =
+                FormationKeeping::new(leader, offsets.clone(), weight, reference.clone());
+            keeping.formation = Some(formation.clone());
+            let path = keeping.astar(follower, &[]).unwrap();
+            let (last, plain_last) = (path[path.len() - 1], plain_path[plain_path.len() - 1]);
+            assert_eq!(last.duration.0, plain_last.duration.0);
+            assert_eq!(last.cost, plain_last.cost);
+            // Marching one row below the leader the whole way
+            let penalty: usize = path
+                .iter()
+                .map(|cell| formation.penalty(follower, cell.location.origin, cell.duration))
+                .sum();
+            assert_eq!(penalty, 0);
+        }
+        // Out of place, the follower pays for every tick by how far off it is
+        let formation = FormationKeeping::new(leader, offsets, 3, reference);
+        assert_eq!(formation.penalty(follower, Pair(0, 3), Pair(0, 0)), 2 * 3);
+        assert_eq!(
+            formation.penalty(follower, Pair(0, 3), Pair(0, 1)),
+            (2 + 3) * 3
+        );
+    }
+
+    #[test]
+    fn keeping_squeezes_through_a_corridor_and_re_forms() {
+        // Only the middle row gets through, so the follower below the leader has to leave its place
+        let rows = ["....###....", "...........", "....###...."];
+        let (leader, follower) = (Pair(0, 1), Pair(0, 2));
+        let init = |destination: Pair| {
+            AStar::init(
+                vec![leader, follower],
+                vec![destination],
+                Pair(0, 0),
+                testing::parse(&rows),
+            )
+        };
+        let reference = init(Pair(10, 1)).astar(leader, &[]).unwrap();
+        let plain_path = init(Pair(10, 2)).astar(follower, &[]).unwrap();
+        let offsets = HashMap::from([(follower, Offset(0, 1))]);
+        let formation = FormationKeeping::new(leader, offsets, 3, reference.clone());
+        let mut keeping = init(Pair(10, 2));
+        keeping.formation = Some(formation.clone());
+        let path = keeping.astar(follower, &[]).unwrap();
+        let penalty = |path: &Path| -> usize {
+            path.iter()
+                .map(|cell| formation.penalty(follower, cell.location.origin, cell.duration))
+                .sum()
+        };
+        // Plain A* is as happy to climb into the leader's row right away
+        assert_eq!(path.len(), plain_path.len());
+        assert_ne!(path, plain_path);
+        assert!(penalty(&path) < penalty(&plain_path));
+        // Leaves its row one step before the corridor and is back one step after it
+        for cell in &path {
+            let Pair(x, y) = cell.location.origin;
+            assert!(y == 2 || (3..=7).contains(&x), "{:?}", path);
+        }
+        let after: Vec<&ScoredCell> = path
+            .iter()
+            .filter(|cell| cell.location.origin.0 > 6 && cell.location.origin.1 == 2)
+            .collect();
+        assert!(!after.is_empty());
+        for cell in after {
+            for time in cell.duration.0..=cell.duration.1 {
+                let lead = formation.leader_at(time).unwrap();
+                assert_eq!(cell.location.origin.1, lead.1 + 1);
+            }
+        }
+        let (last, lead) = (path[path.len() - 1], reference[reference.len() - 1]);
+        assert_eq!(
+            Offset::from(last.location.origin),
+            Offset::from(lead.location.origin) + Offset(0, 1)
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use crate::grid::Grid;
+use crate::prelude::*;
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+pub type GoalPredicate = Arc<dyn Fn(Rect) -> bool + Send + Sync>;
+
+// Where a unit may end up to count as arrived, for orders like "move into this area" or "get
+// within range 3 of the enemy". Goals are slots like plain destinations: any unit may end in any
+// of them, and the search stops at the first location satisfying one.
+#[derive(Clone)]
+pub enum Goal {
+    // The unit's origin on this cell, as a plain destination
+    Cell(Pair),
+    // The whole unit inside the rect
+    Area(Rect),
+    // Any part of the unit within `range` Manhattan steps of any part of the target: a diamond
+    // around a single cell, a rect with cut corners around a bigger one
+    Within { target: Rect, range: usize },
+    // The unit's origin on any of these cells
+    Cells(HashSet<Pair>),
+    // Whatever the caller says, checked on every origin of the grid to build the heuristic
+    Predicate(GoalPredicate),
+}
+
+impl Goal {
+    pub fn contains(&self, location: Rect) -> bool {
+        match self {
+            Goal::Cell(cell) => location.origin == *cell,
+            Goal::Area(area) => {
+                area.contains(location.origin) && area.contains(location.max_coord())
+            }
+            Goal::Within { target, range } => location.distance(*target) <= *range,
+            Goal::Cells(cells) => cells.contains(&location.origin),
+            Goal::Predicate(predicate) => predicate(location),
+        }
+    }
+
+    // Lower bound on the steps from `location` into the goal, none if the goal can't tell
+    pub fn distance(&self, location: Rect) -> Option<usize> {
+        let steps = |to: Pair| location.origin.0.abs_diff(to.0) + location.origin.1.abs_diff(to.1);
+        match self {
+            Goal::Cell(cell) => Some(steps(*cell)),
+            Goal::Area(area) => Some(location.distance(*area)),
+            Goal::Within { target, range } => {
+                Some(location.distance(*target).saturating_sub(*range))
+            }
+            Goal::Cells(cells) => cells.iter().map(|cell| steps(*cell)).min(),
+            Goal::Predicate(predicate) => predicate(location).then_some(0),
+        }
+    }
+
+    // Clear origins where a unit with `extent` satisfies the goal
+    pub fn origins(&self, grid: &Grid<CellInfo>, extent: Pair) -> Vec<Pair> {
+        let last = grid.effective_extent(extent);
+        // Origins outside this box can't satisfy the goal
+        let (low, high) = match self {
+            Goal::Cell(cell) => (*cell, *cell),
+            Goal::Area(area) => (area.origin, area.max_coord()),
+            Goal::Within { target, range } => (
+                Pair(
+                    target.origin.0.saturating_sub(range + extent.0),
+                    target.origin.1.saturating_sub(range + extent.1),
+                ),
+                target.max_coord() + Pair(*range, *range),
+            ),
+            Goal::Cells(cells) => {
+                let mut out: Vec<Pair> = cells
+                    .iter()
+                    .filter(|cell| self.is_open(grid, cell.extend(extent)))
+                    .copied()
+                    .collect();
+                out.sort();
+                return out;
+            }
+            Goal::Predicate(_) => (Pair(0, 0), last),
+        };
+        let mut out = Vec::new();
+        for x in low.0..=high.0.min(last.0) {
+            for y in low.1..=high.1.min(last.1) {
+                let location = Pair(x, y).extend(extent);
+                if self.is_open(grid, location) {
+                    out.push(location.origin);
+                }
+            }
+        }
+        out
+    }
+
+    fn is_open(&self, grid: &Grid<CellInfo>, location: Rect) -> bool {
+        grid.in_bounds(location) && grid.is_clear(location) && self.contains(location)
+    }
+}
+
+// Predicates only compare equal to themselves
+impl PartialEq for Goal {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Goal::Cell(a), Goal::Cell(b)) => a == b,
+            (Goal::Area(a), Goal::Area(b)) => a == b,
+            (
+                Goal::Within { target, range },
+                Goal::Within {
+                    target: other_target,
+                    range: other_range,
+                },
+            ) => target == other_target && range == other_range,
+            (Goal::Cells(a), Goal::Cells(b)) => a == b,
+            (Goal::Predicate(a), Goal::Predicate(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Goal {}
+
+impl Debug for Goal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Goal::Cell(cell) => write!(f, "Cell({:?})", cell),
+            Goal::Area(area) => write!(f, "Area({:?})", area),
+            Goal::Within { target, range } => write!(f, "Within({:?}, {})", target, range),
+            Goal::Cells(cells) => write!(f, "Cells({} cells)", cells.len()),
+            Goal::Predicate(_) => write!(f, "Predicate"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::astar::AStar;
+    use crate::cbs::solve_mapf;
+    use crate::heuristic::HeuristicCache;
+    use crate::testing;
+
+    fn goals() -> Vec<Goal> {
+        vec![
+            Goal::Cell(Pair(11, 8)),
+            Goal::Area(Pair(4, 4).extend(Pair(1, 2))),
+            Goal::Within {
+                target: Pair(9, 2).extend(Pair(0, 0)),
+                range: 2,
+            },
+            Goal::Cells(HashSet::from([Pair(0, 8), Pair(11, 4), Pair(2, 2)])),
+            Goal::Predicate(Arc::new(|location: Rect| location.origin.1 == 6)),
+        ]
+    }
+
+    #[test]
+    fn searches_end_in_the_region_at_djikstra_cost() {
+        let grid = testing::maze();
+        let extent = Pair(0, 0);
+        let origins = testing::clear_origins(&grid, extent);
+        for goal in goals() {
+            let targets: Vec<Rect> = goal
+                .origins(&grid, extent)
+                .into_iter()
+                .map(|origin| origin.extend(extent))
+                .collect();
+            let exact = grid.djikstra_to_any(&targets);
+            let mut astar = AStar::init(origins.clone(), Vec::new(), extent, testing::maze());
+            astar.retarget_goals(vec![goal.clone()], &HeuristicCache::new());
+            for origin in origins.iter().step_by(4) {
+                let path = astar.astar(*origin, &[]).unwrap();
+                let last = path[path.len() - 1];
+                assert!(goal.contains(last.location), "{:?} from {:?}", goal, origin);
+                let locations: Vec<Rect> = path.iter().map(|cell| cell.location).collect();
+                let cost = testing::walk_cost(&grid, &locations);
+                assert_eq!(cost, exact[*origin], "{:?} from {:?}", goal, origin);
+            }
+        }
+        // A cell goal is a plain destination
+        let plain = AStar::init(origins.clone(), vec![Pair(11, 8)], extent, testing::maze());
+        let mut cell = AStar::init(origins.clone(), Vec::new(), extent, testing::maze());
+        cell.retarget_goals(vec![Goal::Cell(Pair(11, 8))], &HeuristicCache::new());
+        for origin in origins.iter().step_by(4) {
+            assert_eq!(plain.astar(*origin, &[]), cell.astar(*origin, &[]));
+        }
+    }
+
+    #[test]
+    fn units_share_out_region_slots() {
+        let origins = vec![Pair(0, 0), Pair(11, 0), Pair(0, 6)];
+        let goals = vec![
+            Goal::Cell(Pair(11, 8)),
+            Goal::Area(Pair(4, 4).extend(Pair(1, 0))),
+            Goal::Cells(HashSet::from([Pair(0, 8)])),
+        ];
+        let mut astar = AStar::init(origins, Vec::new(), Pair(0, 0), testing::maze());
+        astar.retarget_goals(goals.clone(), &HeuristicCache::new());
+        let ends: HashSet<Pair> = solve_mapf(&astar)
+            .iter()
+            .map(|path| {
+                let last = path[path.len() - 1].location;
+                assert!(goals.iter().any(|goal| goal.contains(last)));
+                last.origin
+            })
+            .collect();
+        assert_eq!(ends.len(), 3);
+    }
+}
@@ -1,19 +1,51 @@
 use crate::prelude::*;
+use crate::zoc::Zoc;
 use core::panic;
 use std::{
-    cell,
-    collections::{BinaryHeap, HashMap},
+    collections::BinaryHeap,
+    hash::{DefaultHasher, Hash, Hasher},
     ops::{Index, IndexMut, Sub},
-    usize,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
-#[derive(PartialEq, Eq)]
+// Source of grid ids and versions, global so that no two grids (or states of a grid) share one
+static STAMPS: AtomicU64 = AtomicU64::new(0);
+
+fn next_stamp() -> u64 {
+    STAMPS.fetch_add(1, Ordering::Relaxed)
+}
+
 pub struct Grid<T> {
     data: Vec<T>,
     extent: Pair,
+    // Which grid this is, and a stamp that changes whenever the grid may have been changed.
+    // Caches key on these.
+    id: u64,
+    version: u64,
+}
+
+// Equal contents, no matter the bookkeeping
+impl<T: PartialEq> PartialEq for Grid<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.extent == other.extent && self.data == other.data
+    }
 }
 
+impl<T: Eq> Eq for Grid<T> {}
+
 impl<T> Grid<T> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn touch(&mut self) {
+        self.version = next_stamp()
+    }
+
     // Max coordinate
     pub fn extent(&self) -> Pair {
         self.extent
@@ -40,6 +72,16 @@ impl<T> Grid<T> {
     }
 }
 
+impl<T: Hash> Grid<T> {
+    // Same for grids with equal contents, whichever instance or version they are
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.extent.hash(&mut hasher);
+        self.data.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
 impl<T> Index<Pair> for Grid<T> {
     type Output = T;
     fn index(&self, index: Pair) -> &Self::Output {
@@ -49,6 +91,7 @@ impl<T> Index<Pair> for Grid<T> {
 
 impl<T> IndexMut<Pair> for Grid<T> {
     fn index_mut(&mut self, index: Pair) -> &mut Self::Output {
+        self.touch();
         let idx = self.pair_to_usize(index);
         &mut self.data[idx]
     }
@@ -57,7 +100,12 @@ impl<T> IndexMut<Pair> for Grid<T> {
 impl<T: Copy> Grid<T> {
     pub fn init(extent: Pair, value: T) -> Grid<T> {
         let data = vec![value; (extent.0 + 1) * (extent.1 + 1)];
-        Grid { data, extent }
+        Grid {
+            data,
+            extent,
+            id: next_stamp(),
+            version: next_stamp(),
+        }
     }
 
     pub fn indexed_iter(&self) -> impl Iterator<Item = (Pair, &T)> {
@@ -68,6 +116,7 @@ impl<T: Copy> Grid<T> {
     }
 
     pub fn indexed_iter_mut(&mut self) -> impl Iterator<Item = (Pair, &mut T)> {
+        self.touch();
         let extent = self.extent;
         self.data.iter_mut().enumerate().map(move |(index, i)| {
             let position = Grid::<T>::usize_to_pair_(extent, index);
@@ -79,13 +128,29 @@ impl<T: Copy> Grid<T> {
 impl Grid<CellInfo> {
     // Max origin for a unit with extent `extent`
     pub fn effective_extent(&self, extent: Pair) -> Pair {
-        Pair(self.extent().0.sub(extent.0), self.extent().0.sub(extent.1))
+        Pair(self.extent().0.sub(extent.0), self.extent().1.sub(extent.1))
     }
 
     pub fn effective_size(&self, extent: Pair) -> Pair {
         self.effective_extent(extent) + Pair(1, 1)
     }
 
+    // Every origin where a unit of `extent` fits, row by row
+    pub fn clear_origins(&self, extent: Pair) -> impl Iterator<Item = Pair> + '_ {
+        let max = self.effective_extent(extent);
+        (0..=max.1)
+            .flat_map(move |y| (0..=max.0).map(move |x| Pair(x, y)))
+            .filter(move |origin| self.is_clear(origin.extend(extent)))
+    }
+
+    // Least a unit of `extent` pays for any step, so Manhattan distance times this never
+    // overestimates a route
+    pub fn min_step(&self, extent: Pair) -> usize {
+        let cheapest = self.indexed_iter().map(|(_, cell)| cell.cost).min();
+        let size = extent + Pair(1, 1);
+        cheapest.unwrap_or(0) * size.0 * size.1
+    }
+
     // Accounts for unit size
     pub fn in_bounds(&self, rect: Rect) -> bool {
         rect.max_coord().0 <= self.extent().0 && rect.max_coord().1 <= self.extent().1
@@ -133,6 +198,12 @@ impl Grid<CellInfo> {
         }
     }
 
+    pub fn set_cost(&mut self, rect: Rect, cost: usize) {
+        for cell in rect.cells() {
+            self[cell].cost = cost
+        }
+    }
+
     pub fn cost(&self, rect: Rect) -> usize {
         let mut total = 0;
         for tile in rect.cells() {
@@ -141,65 +212,118 @@ impl Grid<CellInfo> {
         total
     }
 
+    // Times a walk through adjacent rects the way A* would, moving as soon as possible.
+    // The cost of each scored cell is its departure time.
+    pub fn timed_path(&self, locations: &[Rect]) -> Path {
+        let mut path: Path = Vec::with_capacity(locations.len());
+        for location in locations {
+            let duration = match path.last() {
+                None => Pair(0, 0),
+                Some(prev) => Pair(prev.duration.1 + 1, prev.duration.1 + self.cost(*location)),
+            };
+            path.push(ScoredCell {
+                location: *location,
+                duration,
+                cost: duration.1,
+                leg: 0,
+            });
+        }
+        path
+    }
+
+    // A route of origins for a unit of `extent`, timed like an unconstrained A* path
+    pub fn timed_route(&self, route: &[Pair], extent: Pair) -> Path {
+        let locations: Vec<Rect> = route.iter().map(|origin| origin.extend(extent)).collect();
+        self.timed_path(&locations)
+    }
+
+    // Tick a unit on `path` is done entering its last cell, which takes that cell's cost
+    pub fn finished(&self, path: &Path) -> usize {
+        let last = path[path.len() - 1];
+        match path.len() {
+            1 => last.duration.0,
+            _ => (last.duration.0 + self.cost(last.location)).saturating_sub(1),
+        }
+    }
+
+    // Cost to reach `to` from every origin, usize::MAX where it can't be reached
     pub fn djikstra(&self, to: Rect) -> Grid<usize> {
-        let size = self.effective_size(to.extent);
+        self.search(&[to], true, &[], None)
+    }
+
+    // Cost to reach the nearest of `targets` from every origin. The targets must share an extent.
+    pub fn djikstra_to_any(&self, targets: &[Rect]) -> Grid<usize> {
+        self.search(targets, true, &[], None)
+    }
+
+    // As djikstra_to_any(), never stepping onto a rect overlapping one in `avoid`, and only
+    // passing through a zone of control the way its rule allows
+    pub fn djikstra_to_any_avoiding(
+        &self,
+        targets: &[Rect],
+        avoid: &[Rect],
+        zoc: Option<&Zoc>,
+    ) -> Grid<usize> {
+        self.search(targets, true, avoid, zoc)
+    }
+
+    // Cost to reach every origin from `from`
+    pub fn djikstra_from(&self, from: Rect) -> Grid<usize> {
+        self.search(&[from], false, &[], None)
+    }
+
+    // Moving into a rect costs the rect, so distances depend on direction. Searching backwards
+    // from the sources charges the cell we step off instead of the one we step onto.
+    // All sources must share an extent.
+    fn search(
+        &self,
+        sources: &[Rect],
+        backwards: bool,
+        avoid: &[Rect],
+        zoc: Option<&Zoc>,
+    ) -> Grid<usize> {
+        let extent = sources.first().map_or(Pair(0, 0), |source| source.extent);
+        let size = self.effective_size(extent);
         let mut open = BinaryHeap::with_capacity(size.0 * size.1);
-        open.push(DjikstraCell {
-            location: to,
-            cost: 0,
-        });
-        let mut closed = Grid::init(self.effective_extent(to.extent), usize::MAX);
-        while !open.is_empty() {
-            let cell = match open.pop() {
-                Some(c) => c,
-                None => break,
-            };
+        for source in sources {
+            open.push(DjikstraCell {
+                location: *source,
+                cost: 0,
+            });
+        }
+        let mut closed = Grid::init(self.effective_extent(extent), usize::MAX);
+        while let Some(cell) = open.pop() {
             // If the cell has already been fully resolved
             if closed[cell.location.origin] < usize::MAX {
                 continue;
             }
             closed[cell.location.origin] = cell.cost;
+            // Either way round, a unit stopped by a zone doesn't go on from it
+            let stops = zoc.is_some_and(|zoc| zoc.stops(cell.location));
+            if stops && !sources.contains(&cell.location) {
+                continue;
+            }
             for neighbor in self.neighbors(cell.location) {
+                if avoid.iter().any(|other| other.intersects(neighbor)) {
+                    continue;
+                }
                 // If the neighbor has not been fully resolved yet
                 if closed[neighbor.origin] == usize::MAX {
-                    // Cost of self, because the cost is to move *to* self
-                    let new_cost = cell.cost + self.cost(cell.location);
+                    let step = if backwards {
+                        // Cost of self, because the cost is to move *to* self
+                        self.cost(cell.location) + zoc.map_or(0, |zoc| zoc.toll(cell.location))
+                    } else {
+                        self.cost(neighbor) + zoc.map_or(0, |zoc| zoc.toll(neighbor))
+                    };
                     open.push(DjikstraCell {
                         location: neighbor,
-                        cost: new_cost,
+                        cost: cell.cost + step,
                     });
                 }
             }
         }
         closed
     }
-
-    pub fn floyd_warshall(&self, extent: Pair) -> Grid<usize> {
-        let max_idx = self.pair_to_usize(self.extent());
-        let mut distances = Grid::init(Pair(max_idx, max_idx), usize::MAX);
-        for (origin, _) in self.indexed_iter() {
-            for neighbor in self.neighbors(Rect { origin, extent }) {
-                let idx = Pair(
-                    self.pair_to_usize(origin),
-                    self.pair_to_usize(neighbor.origin),
-                );
-                distances[idx] = self.cost(neighbor);
-            }
-        }
-        for idx in 0..=max_idx {
-            distances[Pair(idx, idx)] = 0
-        }
-        for j in 0..=max_idx {
-            for i in 0..=max_idx {
-                for k in 0..=max_idx {
-                    if distances[Pair(i, k)] > distances[Pair(i, j)] + distances[Pair(j, k)] {
-                        distances[Pair(i, k)] = distances[Pair(i, j)] + distances[Pair(j, k)]
-                    }
-                }
-            }
-        }
-        distances
-    }
 }
 
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -222,3 +346,20 @@ impl PartialOrd for DjikstraCell {
         Some(self.cmp(other))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn tall_grids_keep_their_height() {
+        // Taller than wide, so taking the x extent for y would cut off the bottom rows
+        let grid = testing::open(Pair(2, 7));
+        assert_eq!(grid.effective_extent(Pair(1, 2)), Pair(1, 5));
+        assert_eq!(grid.effective_size(Pair(0, 0)), Pair(3, 8));
+        let from = grid.djikstra_from(Pair(0, 0).extend(Pair(0, 1)));
+        assert_eq!(from.extent(), Pair(2, 6));
+        assert_eq!(from[Pair(2, 6)], 2 * 2 + 6 * 2);
+    }
+}
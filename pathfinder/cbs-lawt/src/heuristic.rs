@@ -0,0 +1,191 @@
+use crate::grid::Grid;
+use crate::landmark::{LandmarkBounds, Landmarks};
+use crate::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+// How a search estimates the remaining cost to its destinations
+#[derive(PartialEq, Eq)]
+pub enum Heuristic {
+    // Exact cost to the nearest destination, one Djikstra per destination
+    Exact(Grid<usize>),
+    // Lower bounds from precomputed landmarks, cheap to build and to retarget
+    Landmarks(Arc<Landmarks>, LandmarkBounds),
+}
+
+impl Heuristic {
+    // usize::MAX if no destination can be reached
+    pub fn estimate(&self, origin: Pair) -> usize {
+        match self {
+            Heuristic::Exact(distances) => distances[origin],
+            Heuristic::Landmarks(landmarks, bounds) => landmarks.estimate_bounds(origin, bounds),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct HeuristicKey {
+    // Content hash of the grid, so separately built grids with the same terrain share fields
+    contents: u64,
+    // The rect carries the unit extent
+    destination: Rect,
+    class: MovementClass,
+}
+
+#[derive(Default)]
+struct Entries {
+    fields: HashMap<HeuristicKey, Arc<Grid<usize>>>,
+    // Version and content hash each grid was last seen at, so hits don't rehash the grid
+    contents: HashMap<u64, (u64, u64)>,
+}
+
+impl Entries {
+    // Keeps only the fields of contents some grid still has
+    fn prune(&mut self) {
+        let current: HashSet<u64> = self.contents.values().map(|(_, hash)| *hash).collect();
+        self.fields.retain(|key, _| current.contains(&key.contents));
+    }
+}
+
+// Distance fields to single destinations, shared by every solver (and thread) holding a clone.
+// Entries are keyed on the grid contents, so solvers owning equal grids hit each other's fields,
+// and blocking a cell or changing a cost invalidates them.
+#[derive(Clone, Default)]
+pub struct HeuristicCache {
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl HeuristicCache {
+    pub fn new() -> HeuristicCache {
+        HeuristicCache::default()
+    }
+
+    // Cost to reach `destination` from every origin, as Grid::djikstra
+    pub fn get(
+        &self,
+        grid: &Grid<CellInfo>,
+        destination: Rect,
+        class: MovementClass,
+    ) -> Arc<Grid<usize>> {
+        let key = HeuristicKey {
+            contents: self.contents(grid),
+            destination,
+            class,
+        };
+        if let Some(field) = self.entries.lock().unwrap().fields.get(&key) {
+            return Arc::clone(field);
+        }
+        // Not under the lock, other solvers may keep hitting the cache meanwhile
+        let field = Arc::new(grid.djikstra(destination));
+        let mut entries = self.entries.lock().unwrap();
+        Arc::clone(entries.fields.entry(key).or_insert(field))
+    }
+
+    // Hashes `grid` if it changed since it was last seen, dropping the fields nobody can hit
+    fn contents(&self, grid: &Grid<CellInfo>) -> u64 {
+        let seen = self
+            .entries
+            .lock()
+            .unwrap()
+            .contents
+            .get(&grid.id())
+            .copied();
+        match seen {
+            Some((version, hash)) if version == grid.version() => hash,
+            _ => {
+                let hash = grid.content_hash();
+                let mut entries = self.entries.lock().unwrap();
+                entries.contents.insert(grid.id(), (grid.version(), hash));
+                entries.prune();
+                hash
+            }
+        }
+    }
+
+    // Drops the fields of older versions of `grid`. get() does this lazily.
+    pub fn invalidate(&self, grid: &Grid<CellInfo>) {
+        self.contents(grid);
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.fields.clear();
+        entries.contents.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::astar::AStar;
+    use crate::pibt::PIBT;
+    use crate::testing;
+
+    #[test]
+    fn cached_fields_match_djikstra_and_follow_edits() {
+        let mut grid = testing::maze();
+        let cache = HeuristicCache::new();
+        let destination = Pair(11, 8).extend(Pair(0, 0));
+        let class = MovementClass::default();
+        let field = cache.get(&grid, destination, class);
+        assert!(*field == grid.djikstra(destination));
+        assert!(Arc::ptr_eq(&field, &cache.get(&grid, destination, class)));
+        assert_eq!(cache.len(), 1);
+
+        grid.set_blocked(Pair(11, 7).extend(Pair(0, 0)), true);
+        let edited = cache.get(&grid, destination, class);
+        assert!(!Arc::ptr_eq(&field, &edited));
+        assert!(*edited == grid.djikstra(destination));
+        // The stale field went with the miss
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn solvers_with_equal_grids_share_fields() {
+        let cache = HeuristicCache::new();
+        let destinations = vec![Pair(11, 8), Pair(11, 0)];
+        let astar = AStar::init_cached(
+            vec![Pair(0, 0), Pair(0, 2)],
+            destinations.clone(),
+            Pair(0, 0),
+            testing::maze(),
+            &cache,
+        );
+        assert_eq!(cache.len(), 2);
+        let field = cache.get(
+            &astar.grid,
+            Pair(11, 8).extend(Pair(0, 0)),
+            MovementClass::default(),
+        );
+        // Built on its own grid, which is equal but not the same instance
+        PIBT::init_cached(
+            testing::maze(),
+            vec![Pair(0, 4), Pair(0, 6)],
+            destinations,
+            Pair(0, 0),
+            &cache,
+        );
+        assert_eq!(cache.len(), 2);
+        let mut other = testing::maze();
+        assert!(Arc::ptr_eq(
+            &field,
+            &cache.get(
+                &other,
+                Pair(11, 8).extend(Pair(0, 0)),
+                MovementClass::default()
+            )
+        ));
+        // Editing one grid leaves the fields the others still hit
+        other.set_blocked(Pair(11, 7).extend(Pair(0, 0)), true);
+        cache.invalidate(&other);
+        assert_eq!(cache.len(), 2);
+    }
+}
@@ -0,0 +1,549 @@
+use crate::grid::Grid;
+use crate::prelude::*;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+// Border segments at least this long get an entrance at each end instead of one in the middle
+const LONG_SEGMENT: usize = 6;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Edge {
+    to: Pair,
+    cost: usize,
+    // Origins walked through after leaving the edge's start, ending with `to`
+    cells: Vec<Pair>,
+}
+
+// Cost and link per origin reached by a search confined to one cluster. Forwards the link is the
+// previous origin, backwards it is the next origin on the way to the source.
+type LocalSearch = HashMap<Pair, (usize, Pair)>;
+
+// The abstract node reached, and the origins walked to get there
+type Hop = (Pair, Vec<Pair>);
+
+// How the abstract search reached a node, the cells are only traced for the final route
+#[derive(Clone, Copy)]
+enum Via {
+    Start,
+    Edge(usize),
+    Goal,
+}
+
+// Hierarchical pathfinding (HPA*). Unit origins are split into square clusters, adjacent clusters
+// are joined by entrances along their shared border, and entrances in the same cluster are joined
+// by their shortest path inside it. Queries search the small abstract graph, and the refined path
+// is stitched together from the paths cached on its edges. Unlike textbook HPA*, refinement runs
+// no AStar between entrances: the edges already hold those paths from the build, and searching
+// again per query would cost the time the hierarchy is there to save.
+// Routes are the cheapest through the entrances, not the cheapest overall. Each border crossed
+// can add a detour to an entrance, at most the cluster size in steps on open ground.
+pub struct Hpa {
+    grid: Grid<CellInfo>,
+    extent: Pair,
+    cluster_size: usize,
+    // Entrance pairs on the border of two adjacent clusters, (cell in a, cell in b) for key (a, b)
+    borders: HashMap<(Pair, Pair), Vec<(Pair, Pair)>>,
+    edges: HashMap<Pair, Vec<Edge>>,
+    // No step costs less, so Manhattan distance times this guides the abstract search
+    min_step: usize,
+}
+
+impl Hpa {
+    pub fn new(grid: Grid<CellInfo>, extent: Pair, cluster_size: usize) -> Hpa {
+        if cluster_size == 0 {
+            panic!("Clusters must be at least one cell wide!")
+        }
+        let mut hpa = Hpa {
+            grid,
+            extent,
+            cluster_size,
+            borders: HashMap::new(),
+            edges: HashMap::new(),
+            min_step: 0,
+        };
+        hpa.update_min_step();
+        let all: HashSet<Pair> = hpa.clusters().collect();
+        hpa.rebuild(&all);
+        hpa
+    }
+
+    pub fn grid(&self) -> &Grid<CellInfo> {
+        &self.grid
+    }
+
+    pub fn extent(&self) -> Pair {
+        self.extent
+    }
+
+    // Number of entrances in the abstract graph
+    pub fn node_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    fn update_min_step(&mut self) {
+        self.min_step = self.grid.min_step(self.extent);
+    }
+
+    // Clusters
+
+    fn cluster_count(&self) -> Pair {
+        let size = self.grid.effective_size(self.extent);
+        Pair(
+            size.0.div_ceil(self.cluster_size),
+            size.1.div_ceil(self.cluster_size),
+        )
+    }
+
+    fn clusters(&self) -> impl Iterator<Item = Pair> {
+        let count = self.cluster_count();
+        (0..count.1).flat_map(move |y| (0..count.0).map(move |x| Pair(x, y)))
+    }
+
+    fn cluster_of(&self, origin: Pair) -> Pair {
+        Pair(origin.0 / self.cluster_size, origin.1 / self.cluster_size)
+    }
+
+    // Smallest and largest origin in the cluster
+    fn bounds(&self, cluster: Pair) -> (Pair, Pair) {
+        let max = self.grid.effective_extent(self.extent);
+        let min = Pair(cluster.0 * self.cluster_size, cluster.1 * self.cluster_size);
+        let top = Pair(
+            (min.0 + self.cluster_size - 1).min(max.0),
+            (min.1 + self.cluster_size - 1).min(max.1),
+        );
+        (min, top)
+    }
+
+    fn adjacent_clusters(&self, cluster: Pair) -> Vec<Pair> {
+        let count = self.cluster_count();
+        let mut out = Vec::with_capacity(4);
+        if cluster.0 > 0 {
+            out.push(Pair(cluster.0 - 1, cluster.1));
+        }
+        if cluster.1 > 0 {
+            out.push(Pair(cluster.0, cluster.1 - 1));
+        }
+        if cluster.0 + 1 < count.0 {
+            out.push(Pair(cluster.0 + 1, cluster.1));
+        }
+        if cluster.1 + 1 < count.1 {
+            out.push(Pair(cluster.0, cluster.1 + 1));
+        }
+        out
+    }
+
+    fn border_key(a: Pair, b: Pair) -> (Pair, Pair) {
+        if a < b {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    }
+
+    fn is_open(&self, origin: Pair) -> bool {
+        self.grid.is_clear(origin.extend(self.extent))
+    }
+
+    fn in_range(&self, origin: Pair) -> bool {
+        let max = self.grid.effective_extent(self.extent);
+        origin.0 <= max.0 && origin.1 <= max.1
+    }
+
+    // Abstract graph
+
+    // `a` is left of or above `b`
+    fn find_entrances(&self, a: Pair, b: Pair) -> Vec<(Pair, Pair)> {
+        let (min, max) = self.bounds(a);
+        let horizontal = b.0 == a.0 + 1;
+        let crossings: Vec<(Pair, Pair)> = if horizontal {
+            (min.1..=max.1)
+                .map(|y| (Pair(max.0, y), Pair(max.0 + 1, y)))
+                .collect()
+        } else {
+            (min.0..=max.0)
+                .map(|x| (Pair(x, max.1), Pair(x, max.1 + 1)))
+                .collect()
+        };
+        let mut out = Vec::new();
+        let mut segment: Vec<(Pair, Pair)> = Vec::new();
+        for crossing in crossings {
+            if self.is_open(crossing.0) && self.is_open(crossing.1) {
+                segment.push(crossing);
+            } else {
+                Hpa::close_segment(&mut segment, &mut out);
+            }
+        }
+        Hpa::close_segment(&mut segment, &mut out);
+        out
+    }
+
+    fn close_segment(segment: &mut Vec<(Pair, Pair)>, out: &mut Vec<(Pair, Pair)>) {
+        if segment.is_empty() {
+            return;
+        }
+        if segment.len() >= LONG_SEGMENT {
+            out.push(segment[0]);
+            out.push(segment[segment.len() - 1]);
+        } else {
+            out.push(segment[segment.len() / 2]);
+        }
+        segment.clear();
+    }
+
+    fn nodes_of(&self, cluster: Pair) -> Vec<Pair> {
+        let mut nodes = Vec::new();
+        for other in self.adjacent_clusters(cluster) {
+            let key = Hpa::border_key(cluster, other);
+            for (a, b) in self.borders.get(&key).into_iter().flatten() {
+                nodes.push(if key.0 == cluster { *a } else { *b });
+            }
+        }
+        nodes.sort();
+        nodes.dedup();
+        nodes
+    }
+
+    // Recomputes the borders of `affected`, then the edges of every cluster those borders touch
+    fn rebuild(&mut self, affected: &HashSet<Pair>) {
+        let mut touched = affected.clone();
+        for cluster in affected {
+            for other in self.adjacent_clusters(*cluster) {
+                let key = Hpa::border_key(*cluster, other);
+                let entrances = self.find_entrances(key.0, key.1);
+                self.borders.insert(key, entrances);
+                touched.insert(other);
+            }
+        }
+        let cluster_size = self.cluster_size;
+        self.edges.retain(|node, _| {
+            !touched.contains(&Pair(node.0 / cluster_size, node.1 / cluster_size))
+        });
+        let mut touched: Vec<Pair> = touched.into_iter().collect();
+        touched.sort();
+        for cluster in touched {
+            self.build_cluster(cluster);
+        }
+    }
+
+    fn build_cluster(&mut self, cluster: Pair) {
+        let nodes = self.nodes_of(cluster);
+        let bounds = self.bounds(cluster);
+        for node in &nodes {
+            let search = self.local_search(*node, bounds, false);
+            let mut edges = Vec::new();
+            for other in &nodes {
+                if other == node {
+                    continue;
+                }
+                if let Some((cost, _)) = search.get(other) {
+                    edges.push(Edge {
+                        to: *other,
+                        cost: *cost,
+                        cells: Hpa::trace_forwards(&search, *node, *other),
+                    });
+                }
+            }
+            for other_cluster in self.adjacent_clusters(cluster) {
+                let key = Hpa::border_key(cluster, other_cluster);
+                for (a, b) in self.borders.get(&key).into_iter().flatten() {
+                    let (here, there) = if key.0 == cluster { (a, b) } else { (b, a) };
+                    if here == node {
+                        edges.push(Edge {
+                            to: *there,
+                            cost: self.grid.cost(there.extend(self.extent)),
+                            cells: vec![*there],
+                        });
+                    }
+                }
+            }
+            self.edges.insert(*node, edges);
+        }
+    }
+
+    // Local searches
+
+    fn local_search(&self, source: Pair, bounds: (Pair, Pair), backwards: bool) -> LocalSearch {
+        let (min, max) = bounds;
+        let mut reached: LocalSearch = HashMap::new();
+        let mut open = BinaryHeap::new();
+        open.push(Reverse((0, source, source)));
+        while let Some(Reverse((cost, origin, link))) = open.pop() {
+            if reached.contains_key(&origin) {
+                continue;
+            }
+            reached.insert(origin, (cost, link));
+            let location = origin.extend(self.extent);
+            for neighbor in self.grid.neighbors(location) {
+                let next = neighbor.origin;
+                let inside =
+                    min.0 <= next.0 && next.0 <= max.0 && min.1 <= next.1 && next.1 <= max.1;
+                if inside && !reached.contains_key(&next) {
+                    let step = if backwards {
+                        self.grid.cost(location)
+                    } else {
+                        self.grid.cost(neighbor)
+                    };
+                    open.push(Reverse((cost + step, next, origin)));
+                }
+            }
+        }
+        reached
+    }
+
+    // Origins after `from` up to and including `to`, from a forwards search out of `from`
+    fn trace_forwards(search: &LocalSearch, from: Pair, to: Pair) -> Vec<Pair> {
+        let mut cells = vec![to];
+        let mut current = to;
+        while let Some((_, prev)) = search.get(&current) {
+            if *prev == from || *prev == current {
+                break;
+            }
+            cells.push(*prev);
+            current = *prev;
+        }
+        cells.reverse();
+        cells
+    }
+
+    // Origins after `from` up to and including the source of a backwards search
+    fn trace_backwards(search: &LocalSearch, from: Pair) -> Vec<Pair> {
+        let mut cells = Vec::new();
+        let mut current = from;
+        while let Some((_, next)) = search.get(&current) {
+            if *next == current {
+                break;
+            }
+            cells.push(*next);
+            current = *next;
+        }
+        cells
+    }
+
+    // Queries
+
+    fn estimate(&self, from: Pair, to: Pair) -> usize {
+        (from.0.abs_diff(to.0) + from.1.abs_diff(to.1)) * self.min_step
+    }
+
+    // Cost, then each hop of the route
+    fn search(&self, from: Pair, to: Pair) -> Option<(usize, Vec<Hop>)> {
+        if !(self.in_range(from) && self.in_range(to) && self.is_open(from) && self.is_open(to)) {
+            return None;
+        }
+        if from == to {
+            return Some((0, Vec::new()));
+        }
+        let (from_cluster, to_cluster) = (self.cluster_of(from), self.cluster_of(to));
+        let start = self.local_search(from, self.bounds(from_cluster), false);
+        if from_cluster == to_cluster {
+            if let Some((cost, _)) = start.get(&to) {
+                let cells = Hpa::trace_forwards(&start, from, to);
+                return Some((*cost, vec![(to, cells)]));
+            }
+        }
+        let goal = self.local_search(to, self.bounds(to_cluster), true);
+        let start_nodes = self.nodes_of(from_cluster);
+
+        let mut best: HashMap<Pair, usize> = HashMap::from([(from, 0)]);
+        let mut prev: HashMap<Pair, (Pair, Via)> = HashMap::new();
+        let mut done: HashSet<Pair> = HashSet::new();
+        // Lowest estimate first, ties to the deepest node so we don't fan out over equal routes
+        let mut open = BinaryHeap::from([(Reverse(self.estimate(from, to)), 0, from)]);
+        let mut hops: Vec<(Pair, usize, Via)> = Vec::new();
+        while let Some((_, cost, node)) = open.pop() {
+            if !done.insert(node) {
+                continue;
+            }
+            if node == to {
+                break;
+            }
+            hops.clear();
+            if node == from {
+                for other in &start_nodes {
+                    if let Some((step, _)) = start.get(other).filter(|_| *other != from) {
+                        hops.push((*other, *step, Via::Start));
+                    }
+                }
+            }
+            for (idx, edge) in self.edges.get(&node).into_iter().flatten().enumerate() {
+                hops.push((edge.to, edge.cost, Via::Edge(idx)));
+            }
+            if self.cluster_of(node) == to_cluster {
+                if let Some((step, _)) = goal.get(&node) {
+                    hops.push((to, *step, Via::Goal));
+                }
+            }
+            for (next, step, via) in hops.drain(..) {
+                let new_cost = cost + step;
+                if !done.contains(&next) && best.get(&next).is_none_or(|old| new_cost < *old) {
+                    best.insert(next, new_cost);
+                    prev.insert(next, (node, via));
+                    open.push((Reverse(new_cost + self.estimate(next, to)), new_cost, next));
+                }
+            }
+        }
+
+        let cost = *best.get(&to).filter(|_| done.contains(&to))?;
+        let mut route = Vec::new();
+        let mut current = to;
+        while current != from {
+            let (node, via) = prev[&current];
+            let cells = match via {
+                Via::Start => Hpa::trace_forwards(&start, from, current),
+                Via::Edge(idx) => self.edges[&node][idx].cells.clone(),
+                Via::Goal => Hpa::trace_backwards(&goal, node),
+            };
+            route.push((current, cells));
+            current = node;
+        }
+        route.reverse();
+        Some((cost, route))
+    }
+
+    // Cost and the entrances passed on the way, `from` first and `to` last
+    pub fn abstract_route(&self, from: Pair, to: Pair) -> Option<(usize, Vec<Pair>)> {
+        let (cost, hops) = self.search(from, to)?;
+        let mut route = vec![from];
+        route.extend(hops.into_iter().map(|(node, _)| node));
+        Some((cost, route))
+    }
+
+    // Cost and every origin on the way, `from` first and `to` last
+    pub fn route(&self, from: Pair, to: Pair) -> Option<(usize, Vec<Pair>)> {
+        let (cost, hops) = self.search(from, to)?;
+        let mut route = vec![from];
+        for (_, cells) in hops {
+            route.extend(cells);
+        }
+        Some((cost, route))
+    }
+
+    pub fn path(&self, from: Pair, to: Pair) -> Option<Path> {
+        let (_, route) = self.route(from, to)?;
+        Some(self.grid.timed_route(&route, self.extent))
+    }
+
+    // Updates
+
+    // Clusters holding an origin whose footprint overlaps `rect`
+    fn clusters_covering(&self, rect: Rect) -> HashSet<Pair> {
+        let max = self.grid.effective_extent(self.extent);
+        let low = Pair(
+            rect.origin.0.saturating_sub(self.extent.0),
+            rect.origin.1.saturating_sub(self.extent.1),
+        );
+        let high = Pair(rect.max_coord().0.min(max.0), rect.max_coord().1.min(max.1));
+        let mut out = HashSet::new();
+        if low.0 > high.0 || low.1 > high.1 {
+            return out;
+        }
+        let (first, last) = (self.cluster_of(low), self.cluster_of(high));
+        for x in first.0..=last.0 {
+            for y in first.1..=last.1 {
+                out.insert(Pair(x, y));
+            }
+        }
+        out
+    }
+
+    // Only the clusters around `rect` are rebuilt
+    pub fn set_blocked(&mut self, rect: Rect, blocked: bool) {
+        self.grid.set_blocked(rect, blocked);
+        self.rebuild(&self.clusters_covering(rect));
+    }
+
+    pub fn set_cost(&mut self, rect: Rect, cost: usize) {
+        self.grid.set_cost(rect, cost);
+        self.update_min_step();
+        self.rebuild(&self.clusters_covering(rect));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    // Routes are valid walks that cost what they claim, never less than the optimum, and exist
+    // exactly when the destination can be reached
+    fn check_routes(hpa: &Hpa, grid: &Grid<CellInfo>) {
+        let extent = hpa.extent();
+        let origins = testing::clear_origins(grid, extent);
+        for to in origins.iter().step_by(7) {
+            let exact = grid.djikstra(to.extend(extent));
+            for from in origins.iter().step_by(5) {
+                match hpa.route(*from, *to) {
+                    None => assert_eq!(exact[*from], usize::MAX, "{:?} to {:?}", from, to),
+                    Some((cost, route)) => {
+                        let locations: Vec<Rect> =
+                            route.iter().map(|origin| origin.extend(extent)).collect();
+                        assert_eq!(route[0], *from);
+                        assert_eq!(route[route.len() - 1], *to);
+                        assert_eq!(testing::walk_cost(grid, &locations), cost);
+                        assert!(cost >= exact[*from]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn routes_are_valid_and_complete() {
+        check_routes(&Hpa::new(testing::maze(), Pair(0, 0), 4), &testing::maze());
+        for seed in 0..2 {
+            let grid = testing::random(seed, Pair(23, 17), 0.25, 3);
+            let hpa = Hpa::new(testing::random(seed, Pair(23, 17), 0.25, 3), Pair(0, 0), 5);
+            check_routes(&hpa, &grid);
+            let grid = testing::random(seed, Pair(23, 17), 0.15, 1);
+            let hpa = Hpa::new(testing::random(seed, Pair(23, 17), 0.15, 1), Pair(1, 1), 6);
+            check_routes(&hpa, &grid);
+        }
+    }
+
+    #[test]
+    fn open_routes_detour_at_most_a_cluster_per_border() {
+        for (size, cluster_size) in [(Pair(23, 17), 4), (Pair(20, 20), 6), (Pair(13, 25), 7)] {
+            let grid = testing::open(size);
+            let hpa = Hpa::new(testing::open(size), Pair(0, 0), cluster_size);
+            let origins = testing::clear_origins(&grid, Pair(0, 0));
+            for to in origins.iter().step_by(23) {
+                let exact = grid.djikstra(to.extend(Pair(0, 0)));
+                for from in origins.iter().step_by(13) {
+                    let (cost, _) = hpa.route(*from, *to).unwrap();
+                    let (a, b) = (hpa.cluster_of(*from), hpa.cluster_of(*to));
+                    let borders = a.0.abs_diff(b.0) + a.1.abs_diff(b.1);
+                    assert!(cost >= exact[*from]);
+                    assert!(
+                        cost <= exact[*from] + borders * cluster_size,
+                        "{:?} to {:?} costs {} against {}",
+                        from,
+                        to,
+                        cost,
+                        exact[*from]
+                    );
+                    if borders == 0 {
+                        assert_eq!(cost, exact[*from]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn routes_stay_valid_through_updates() {
+        let mut hpa = Hpa::new(testing::maze(), Pair(0, 0), 4);
+        let mut grid = testing::maze();
+        let edits = [
+            (Pair(2, 4).extend(Pair(0, 1)), true),
+            (Pair(6, 7).extend(Pair(0, 0)), true),
+            (Pair(3, 4).extend(Pair(0, 0)), false),
+        ];
+        for (rect, blocked) in edits {
+            hpa.set_blocked(rect, blocked);
+            grid.set_blocked(rect, blocked);
+            hpa.set_cost(Pair(10, 2).extend(Pair(0, 0)), 5);
+            grid.set_cost(Pair(10, 2).extend(Pair(0, 0)), 5);
+            check_routes(&hpa, &grid);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use crate::grid::Grid;
+use crate::prelude::*;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Dir {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+impl Dir {
+    const ALL: [Dir; 4] = [Dir::Left, Dir::Right, Dir::Up, Dir::Down];
+
+    fn next(self, origin: Pair) -> Option<Pair> {
+        match self {
+            Dir::Left => origin.0.checked_sub(1).map(|x| Pair(x, origin.1)),
+            Dir::Right => Some(Pair(origin.0 + 1, origin.1)),
+            Dir::Up => origin.1.checked_sub(1).map(|y| Pair(origin.0, y)),
+            Dir::Down => Some(Pair(origin.0, origin.1 + 1)),
+        }
+    }
+
+    fn back(self) -> Dir {
+        match self {
+            Dir::Left => Dir::Right,
+            Dir::Right => Dir::Left,
+            Dir::Up => Dir::Down,
+            Dir::Down => Dir::Up,
+        }
+    }
+
+    fn is_horizontal(self) -> bool {
+        matches!(self, Dir::Left | Dir::Right)
+    }
+
+    fn sides(self) -> [Dir; 2] {
+        if self.is_horizontal() {
+            [Dir::Up, Dir::Down]
+        } else {
+            [Dir::Left, Dir::Right]
+        }
+    }
+}
+
+// Jump point search for 4-connected movement. On terrain where every step costs the same, many
+// routes tie, so straight runs are skipped over until something forces a turn. Jumps only cross
+// cells costing exactly the cheapest step with no pricier neighbour, anywhere else we stop and
+// expand every direction like a plain A*.
+pub struct Jps<'a> {
+    grid: &'a Grid<CellInfo>,
+    extent: Pair,
+    // Cost of moving onto the cheapest terrain
+    step: usize,
+}
+
+impl Jps<'_> {
+    pub fn new(grid: &Grid<CellInfo>, extent: Pair) -> Jps<'_> {
+        Jps {
+            grid,
+            extent,
+            step: grid.min_step(extent),
+        }
+    }
+
+    fn is_open(&self, origin: Pair) -> bool {
+        let location = origin.extend(self.extent);
+        self.grid.in_bounds(location) && self.grid.is_clear(location)
+    }
+
+    fn step_cost(&self, origin: Pair) -> usize {
+        self.grid.cost(origin.extend(self.extent))
+    }
+
+    fn is_cheap(&self, origin: Pair) -> bool {
+        self.is_open(origin) && self.step_cost(origin) == self.step
+    }
+
+    // Costs the cheapest step, and so does every open neighbour
+    fn is_plain(&self, origin: Pair) -> bool {
+        let uniform = |origin: Pair| !self.is_open(origin) || self.step_cost(origin) == self.step;
+        uniform(origin)
+            && Dir::ALL
+                .iter()
+                .all(|dir| dir.next(origin).is_none_or(uniform))
+    }
+
+    // A side cell best reached through `origin`, since the cell behind it is blocked or pricier.
+    // Pricier terrain breaks the symmetry between routes just like a wall.
+    fn has_forced(&self, origin: Pair, dir: Dir) -> bool {
+        dir.sides().iter().any(|side| {
+            let Some(beside) = side.next(origin).filter(|cell| self.is_open(*cell)) else {
+                return false;
+            };
+            dir.back()
+                .next(beside)
+                .is_none_or(|behind| !self.is_cheap(behind))
+        })
+    }
+
+    // Walks from `from` in `dir` to the next cell worth expanding, with the cost of getting there.
+    // Vertical runs also stop where a horizontal run would find something.
+    fn jump(&self, from: Pair, dir: Dir, goal: Pair) -> Option<(Pair, usize)> {
+        let mut current = from;
+        let mut cost = 0;
+        loop {
+            let next = dir.next(current).filter(|cell| self.is_open(*cell))?;
+            cost += self.step_cost(next);
+            if next == goal || !self.is_plain(next) || self.has_forced(next, dir) {
+                return Some((next, cost));
+            }
+            if !dir.is_horizontal()
+                && dir
+                    .sides()
+                    .iter()
+                    .any(|side| self.jump(next, *side, goal).is_some())
+            {
+                return Some((next, cost));
+            }
+            current = next;
+        }
+    }
+
+    fn estimate(&self, from: Pair, to: Pair) -> usize {
+        (from.0.abs_diff(to.0) + from.1.abs_diff(to.1)) * self.step
+    }
+
+    // Cost and every origin on the way, `from` first and `to` last
+    pub fn route(&self, from: Pair, to: Pair) -> Option<(usize, Vec<Pair>)> {
+        if !(self.is_open(from) && self.is_open(to)) {
+            return None;
+        }
+        let mut best: HashMap<Pair, usize> = HashMap::from([(from, 0)]);
+        let mut prev: HashMap<Pair, (Pair, Dir)> = HashMap::new();
+        let mut done: HashSet<Pair> = HashSet::new();
+        let mut open = BinaryHeap::from([(Reverse(self.estimate(from, to)), 0, from)]);
+        while let Some((_, cost, node)) = open.pop() {
+            if !done.insert(node) {
+                continue;
+            }
+            if node == to {
+                return Some((cost, self.unwind(&prev, from, to)));
+            }
+            // Away from plain terrain every direction may be on a cheapest route
+            let dirs = match prev.get(&node) {
+                Some((_, arrival)) if self.is_plain(node) => {
+                    let [a, b] = arrival.sides();
+                    vec![*arrival, a, b]
+                }
+                _ => Dir::ALL.to_vec(),
+            };
+            for dir in dirs {
+                let Some((next, step)) = self.jump(node, dir, to) else {
+                    continue;
+                };
+                let new_cost = cost + step;
+                if !done.contains(&next) && best.get(&next).is_none_or(|old| new_cost < *old) {
+                    best.insert(next, new_cost);
+                    prev.insert(next, (node, dir));
+                    open.push((Reverse(new_cost + self.estimate(next, to)), new_cost, next));
+                }
+            }
+        }
+        None
+    }
+
+    // Fills in the straight runs between jump points
+    fn unwind(&self, prev: &HashMap<Pair, (Pair, Dir)>, from: Pair, to: Pair) -> Vec<Pair> {
+        let mut route = vec![to];
+        let mut current = to;
+        while current != from {
+            let (node, dir) = prev[&current];
+            let mut cell = current;
+            while cell != node {
+                cell = dir.back().next(cell).expect("Jumps stay on the grid!");
+                route.push(cell);
+            }
+            current = node;
+        }
+        route.reverse();
+        route
+    }
+
+    pub fn path(&self, from: Pair, to: Pair) -> Option<Path> {
+        let (_, route) = self.route(from, to)?;
+        Some(self.grid.timed_route(&route, self.extent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn check_routes(grid: &Grid<CellInfo>, extent: Pair) {
+        let jps = Jps::new(grid, extent);
+        let origins = testing::clear_origins(grid, extent);
+        for from in origins.iter().step_by(17) {
+            let exact = grid.djikstra_from(from.extend(extent));
+            for to in origins.iter().step_by(5) {
+                match jps.route(*from, *to) {
+                    None => assert_eq!(exact[*to], usize::MAX, "{:?} to {:?}", from, to),
+                    Some((cost, route)) => {
+                        let locations: Vec<Rect> =
+                            route.iter().map(|origin| origin.extend(extent)).collect();
+                        assert_eq!(cost, exact[*to], "{:?} to {:?}", from, to);
+                        assert_eq!(testing::walk_cost(grid, &locations), cost);
+                        assert_eq!((route[0], route[route.len() - 1]), (*from, *to));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn routes_match_djikstra() {
+        check_routes(&testing::maze(), Pair(0, 0));
+        for seed in 0..2 {
+            // Uniform terrain, where the jumps do the work
+            check_routes(&testing::random(seed, Pair(19, 14), 0.2, 1), Pair(0, 0));
+            // Rough terrain and larger footprints
+            check_routes(&testing::random(seed, Pair(19, 14), 0.2, 3), Pair(0, 0));
+            check_routes(&testing::random(seed, Pair(19, 14), 0.1, 2), Pair(1, 0));
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use crate::grid::Grid;
+use crate::prelude::*;
+
+// Differential (ALT) heuristics. For a landmark L the triangle inequality gives
+//   d(v, t) >= d(v, L) - d(t, L)  and  d(v, t) >= d(L, t) - d(L, v),
+// so a handful of distance fields bound the distance between any two cells. Moving costs the
+// cell moved onto, so distances to and from a landmark differ and we keep both.
+#[derive(PartialEq, Eq)]
+pub struct Landmarks {
+    extent: Pair,
+    landmarks: Vec<Pair>,
+    // to[i][v] = d(v, L_i), from[i][v] = d(L_i, v)
+    to: Vec<Grid<usize>>,
+    from: Vec<Grid<usize>>,
+    // The grid state the fields were computed on
+    grid: u64,
+    version: u64,
+}
+
+// Per landmark bounds over a set of targets, so estimating against many destinations costs the
+// same as against one.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LandmarkBounds {
+    // Largest d(t, L_i) and smallest d(L_i, t) over reachable targets t
+    max_to: Vec<usize>,
+    min_from: Vec<usize>,
+    // Whether the landmark reaches every target
+    reaches_all: Vec<bool>,
+}
+
+impl Landmarks {
+    // Places `count` landmarks by farthest point selection, for units with extent `extent`.
+    // Farthest point spreads them to the edges of the map, where the bounds are tight for any
+    // route heading towards or away from one. Each landmark costs two Djikstras to build and two
+    // lookups per estimate; on the 100x75 bench 4 to 8 do best, more tighten the bounds too
+    // little to pay for the lookups. Against fixed destinations the exact fields still search
+    // faster, landmarks pay off when the destinations change between searches.
+    pub fn new(grid: &Grid<CellInfo>, extent: Pair, count: usize) -> Landmarks {
+        let mut out = Landmarks::with_landmarks(grid, extent, Vec::new());
+        let Some(first) = grid.clear_origins(extent).next() else {
+            return out;
+        };
+        // The cell farthest from an arbitrary one is a good first landmark
+        let seed = grid.djikstra(first.extend(extent));
+        let mut nearest = seed;
+        for _ in 0..count {
+            let Some(next) = Landmarks::farthest(grid, extent, &nearest) else {
+                break;
+            };
+            out.push(grid, next);
+            let idx = out.landmarks.len() - 1;
+            for (cell, distance) in nearest.indexed_iter_mut() {
+                *distance = (*distance).min(out.to[idx][cell]);
+            }
+        }
+        out
+    }
+
+    pub fn with_landmarks(grid: &Grid<CellInfo>, extent: Pair, landmarks: Vec<Pair>) -> Landmarks {
+        let mut out = Landmarks {
+            extent,
+            landmarks: Vec::with_capacity(landmarks.len()),
+            to: Vec::with_capacity(landmarks.len()),
+            from: Vec::with_capacity(landmarks.len()),
+            grid: grid.id(),
+            version: grid.version(),
+        };
+        for landmark in landmarks {
+            out.push(grid, landmark);
+        }
+        out
+    }
+
+    // Same landmarks, fields recomputed for the current state of `grid`
+    pub fn rebuild(&self, grid: &Grid<CellInfo>) -> Landmarks {
+        Landmarks::with_landmarks(grid, self.extent, self.landmarks.clone())
+    }
+
+    fn push(&mut self, grid: &Grid<CellInfo>, landmark: Pair) {
+        let location = landmark.extend(self.extent);
+        self.to.push(grid.djikstra(location));
+        self.from.push(grid.djikstra_from(location));
+        self.landmarks.push(landmark);
+    }
+
+    // Cells no landmark reaches come first, so every connected region gets one
+    fn farthest(grid: &Grid<CellInfo>, extent: Pair, nearest: &Grid<usize>) -> Option<Pair> {
+        let mut best = None;
+        let mut best_distance = 0;
+        for origin in grid.clear_origins(extent) {
+            let distance = nearest[origin];
+            if distance == usize::MAX {
+                return Some(origin);
+            }
+            if best.is_none() || distance > best_distance {
+                best = Some(origin);
+                best_distance = distance;
+            }
+        }
+        // Every clear cell is already a landmark
+        best.filter(|_| best_distance > 0)
+    }
+
+    pub fn landmarks(&self) -> &[Pair] {
+        &self.landmarks
+    }
+
+    pub fn extent(&self) -> Pair {
+        self.extent
+    }
+
+    // False once the grid has changed, the bounds may then overestimate
+    pub fn is_current(&self, grid: &Grid<CellInfo>) -> bool {
+        self.grid == grid.id() && self.version == grid.version()
+    }
+
+    // Lower bound on the cost of moving from `from` to `to`, usize::MAX if we know it can't be done
+    pub fn estimate(&self, from: Pair, to: Pair) -> usize {
+        self.estimate_bounds(from, &self.bounds(&[to]))
+    }
+
+    pub fn bounds(&self, targets: &[Pair]) -> LandmarkBounds {
+        let mut max_to = vec![0; self.landmarks.len()];
+        let mut min_from = vec![usize::MAX; self.landmarks.len()];
+        let mut reaches_all = vec![!targets.is_empty(); self.landmarks.len()];
+        for (idx, (to, from)) in self.to.iter().zip(&self.from).enumerate() {
+            for target in targets {
+                // Targets in another region are bounded by that region's landmarks
+                if to[*target] < usize::MAX {
+                    max_to[idx] = max_to[idx].max(to[*target]);
+                    min_from[idx] = min_from[idx].min(from[*target]);
+                } else {
+                    reaches_all[idx] = false;
+                }
+            }
+        }
+        LandmarkBounds {
+            max_to,
+            min_from,
+            reaches_all,
+        }
+    }
+
+    // Lower bound on the cost of moving from `from` to the nearest of the bounded targets
+    pub fn estimate_bounds(&self, from: Pair, bounds: &LandmarkBounds) -> usize {
+        let mut estimate = 0;
+        for (idx, (to, from_landmark)) in self.to.iter().zip(&self.from).enumerate() {
+            let (to_landmark, landmark_to) = (to[from], from_landmark[from]);
+            if to_landmark == usize::MAX {
+                // Every target shares a region with the landmark, and we don't
+                if bounds.reaches_all[idx] {
+                    return usize::MAX;
+                }
+                continue;
+            }
+            if bounds.min_from[idx] == usize::MAX {
+                continue;
+            }
+            estimate = estimate
+                .max(to_landmark.saturating_sub(bounds.max_to[idx]))
+                .max(bounds.min_from[idx].saturating_sub(landmark_to));
+        }
+        estimate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::astar::AStar;
+    use crate::testing;
+    use std::sync::Arc;
+
+    #[test]
+    fn estimates_never_exceed_djikstra() {
+        let grid = testing::maze();
+        let extent = Pair(0, 0);
+        let landmarks = Landmarks::new(&grid, extent, 4);
+        assert_eq!(landmarks.landmarks().len(), 4);
+        let origins = testing::clear_origins(&grid, extent);
+        for to in &origins {
+            let exact = grid.djikstra(to.extend(extent));
+            for from in &origins {
+                let estimate = landmarks.estimate(*from, *to);
+                assert!(estimate <= exact[*from], "{:?} to {:?}", from, to);
+            }
+        }
+        // Against several targets at once, bounded by the nearest
+        let targets = [Pair(0, 8), Pair(11, 0), Pair(5, 4)];
+        let rects: Vec<Rect> = targets.iter().map(|to| to.extend(extent)).collect();
+        let exact = grid.djikstra_to_any(&rects);
+        let bounds = landmarks.bounds(&targets);
+        for from in &origins {
+            assert!(landmarks.estimate_bounds(*from, &bounds) <= exact[*from]);
+        }
+    }
+
+    #[test]
+    fn astar_with_landmarks_matches_exact() {
+        let origins = vec![Pair(0, 0), Pair(11, 0), Pair(0, 6)];
+        let destinations = vec![Pair(11, 8), Pair(4, 4), Pair(9, 2)];
+        let extent = Pair(0, 0);
+        let grid = testing::maze();
+        let landmarks = Arc::new(Landmarks::new(&grid, extent, 3));
+        let exact = AStar::init(
+            origins.clone(),
+            destinations.clone(),
+            extent,
+            testing::maze(),
+        );
+        let alt =
+            AStar::init_with_landmarks(origins.clone(), destinations, extent, grid, landmarks);
+        for origin in origins {
+            let arrival = |astar: &AStar| {
+                let path = astar.astar(origin, &[]).unwrap();
+                path[path.len() - 1].duration.0
+            };
+            assert_eq!(arrival(&exact), arrival(&alt));
+        }
+    }
+}
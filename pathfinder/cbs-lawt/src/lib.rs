@@ -1,5 +1,21 @@
+pub mod arena;
 pub mod astar;
 pub mod cbs;
+pub mod constraints;
+pub mod dstar;
+pub mod formation;
+pub mod goal;
 pub mod grid;
+pub mod heuristic;
+pub mod hpa;
+pub mod jps;
+pub mod landmark;
+pub mod objective;
+pub mod oracle;
 pub mod pibt;
 pub mod prelude;
+pub mod reach;
+#[cfg(test)]
+mod testing;
+pub mod worker;
+pub mod zoc;
@@ -1,11 +1,12 @@
 // samply record ./path/to/bin to profile
 use cbs_lawt::astar::AStar;
-use cbs_lawt::cbs::solve_mapf;
 use cbs_lawt::grid::Grid;
 use cbs_lawt::pibt::PIBT;
 use cbs_lawt::prelude::{CellInfo, Pair, Path};
+use cbs_lawt::worker::spawn_mapf;
 use rand::seq::SliceRandom;
 use rand::Rng;
+use std::sync::Arc;
 
 fn formation(size: Pair, spread: usize, offset: Pair) -> Vec<Pair> {
     let mut out = Vec::with_capacity(size.0 * size.1);
@@ -97,8 +98,10 @@ fn main() {
     //let baby_example: AStar =
     //    AStar::init(origins.to_vec(), destinations.to_vec(), Pair(1, 1), grid);
 
-    let test = test_case();
-    let sln = solve_mapf(&test);
+    let test = Arc::new(test_case());
+    let sln = spawn_mapf(Arc::clone(&test))
+        .join()
+        .expect("Solver was cancelled!");
     for (i, path) in sln.iter().enumerate() {
         println!("Solution {}:", i);
         for j in path {
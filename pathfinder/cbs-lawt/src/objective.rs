@@ -0,0 +1,115 @@
+use crate::grid::Grid;
+use crate::prelude::{Path, *};
+use std::collections::HashMap;
+
+// What CBS minimises. Paths are padded to a common end, so everything but the makespan is
+// counted up to each unit's final arrival, the tick it is done entering its goal.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Objective {
+    // Time the last unit is done
+    #[default]
+    Makespan,
+    // Arrival times added up
+    SumOfCosts,
+    // Terrain paid for moving, waiting is free
+    Fuel,
+    // Arrival times scaled by a weight per uid, units without one count once
+    Weighted(HashMap<Pair, usize>),
+}
+
+impl Objective {
+    pub fn cost<P: AsRef<Path>>(&self, grid: &Grid<CellInfo>, paths: &[P]) -> usize {
+        let paths = paths.iter().map(AsRef::as_ref);
+        let arrival = |path: &Path| grid.finished(path);
+        match self {
+            Objective::Makespan => paths
+                .map(|path| path[path.len() - 1].duration.1)
+                .max()
+                .unwrap_or(0),
+            Objective::SumOfCosts => paths.map(arrival).sum(),
+            Objective::Fuel => paths
+                .flat_map(|path| path.iter().skip(1))
+                .map(|scored_cell| grid.cost(scored_cell.location))
+                .sum(),
+            Objective::Weighted(weights) => paths
+                .map(|path| {
+                    let weight = weights.get(&path[0].location.origin).copied();
+                    weight.unwrap_or(1) * arrival(path)
+                })
+                .sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::astar::AStar;
+    use crate::cbs::solve_mapf_with_cost;
+    use crate::goal::Goal;
+    use crate::testing;
+
+    fn at(path: &Path, time: usize) -> Rect {
+        path.iter()
+            .find(|scored_cell| scored_cell.duration.0 <= time && time <= scored_cell.duration.1)
+            .unwrap_or(&path[path.len() - 1])
+            .location
+    }
+
+    #[test]
+    fn costs_follow_their_definitions() {
+        let grid = testing::parse(&["1123"]);
+        let cell = |x: usize, duration: Pair| ScoredCell {
+            location: Pair(x, 0).extend(Pair(0, 0)),
+            duration,
+            cost: 0,
+            leg: 0,
+        };
+        // Waits a tick before each move, is done entering the last cell at 5 and stays until 9
+        let walker = vec![
+            cell(0, Pair(0, 1)),
+            cell(1, Pair(2, 3)),
+            cell(2, Pair(4, 9)),
+        ];
+        let sitter = vec![cell(3, Pair(0, 9))];
+        let paths = [walker, sitter];
+        assert_eq!(Objective::Makespan.cost(&grid, &paths), 9);
+        assert_eq!(Objective::SumOfCosts.cost(&grid, &paths), 5);
+        assert_eq!(Objective::Fuel.cost(&grid, &paths), 1 + 2);
+        let weights = HashMap::from([(Pair(0, 0), 3), (Pair(3, 0), 5)]);
+        assert_eq!(Objective::Weighted(weights).cost(&grid, &paths), 3 * 5);
+    }
+
+    // However low an objective scores a plan, collisions up to the last tick still count
+    #[test]
+    fn every_objective_gives_collision_free_plans() {
+        let objectives = [
+            Objective::Makespan,
+            Objective::SumOfCosts,
+            Objective::Fuel,
+            Objective::Weighted(HashMap::from([(Pair(0, 0), 0), (Pair(6, 0), 0)])),
+        ];
+        for objective in objectives {
+            // Each has to touch the far end, so one steps aside into a pocket to let the other by
+            let mut astar = AStar::init(
+                vec![Pair(0, 0), Pair(6, 0)],
+                vec![Pair(1, 1), Pair(5, 1)],
+                Pair(0, 0),
+                testing::parse(&[".......", "#.###.#"]),
+            );
+            astar.set_waypoints(Pair(0, 0), vec![Goal::Cell(Pair(6, 0))]);
+            astar.set_waypoints(Pair(6, 0), vec![Goal::Cell(Pair(0, 0))]);
+            astar.objective = objective.clone();
+            let (paths, cost) = solve_mapf_with_cost(&astar);
+            assert_eq!(cost, objective.cost(&astar.grid, &paths));
+            let end = paths
+                .iter()
+                .map(|path| path[path.len() - 1].duration.1)
+                .max();
+            for time in 0..=end.unwrap() {
+                let (a, b) = (at(&paths[0], time), at(&paths[1], time));
+                assert!(!a.intersects(b), "{:?} at {}", objective, time);
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::grid::Grid;
+use crate::prelude::*;
+use std::collections::HashMap;
+use std::mem::size_of;
+
+// Distances between arbitrary pairs of cells, one Djikstra per destination computed on first use.
+// Rows are evicted least recently used first once they would take more than the memory limit.
+pub struct DistanceOracle<'a> {
+    grid: &'a Grid<CellInfo>,
+    extent: Pair,
+    max_rows: usize,
+    rows: HashMap<Pair, Row>,
+    clock: u64,
+}
+
+struct Row {
+    // Cost to reach the row's destination from every origin
+    distances: Grid<usize>,
+    last_used: u64,
+}
+
+impl DistanceOracle<'_> {
+    // Always keeps at least one row, whatever the limit
+    pub fn new(grid: &Grid<CellInfo>, extent: Pair, memory_limit: usize) -> DistanceOracle<'_> {
+        let size = grid.effective_size(extent);
+        let row_bytes = size.0 * size.1 * size_of::<usize>();
+        DistanceOracle {
+            grid,
+            extent,
+            max_rows: (memory_limit / row_bytes).max(1),
+            rows: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn max_rows(&self) -> usize {
+        self.max_rows
+    }
+
+    pub fn cached_rows(&self) -> usize {
+        self.rows.len()
+    }
+
+    fn valid(&self, origin: Pair) -> bool {
+        let location = origin.extend(self.extent);
+        self.grid.in_bounds(location) && self.grid.is_clear(location)
+    }
+
+    fn evict(&mut self) {
+        while self.rows.len() >= self.max_rows {
+            let oldest = self
+                .rows
+                .iter()
+                .min_by_key(|(_, row)| row.last_used)
+                .map(|(to, _)| *to);
+            match oldest {
+                Some(to) => self.rows.remove(&to),
+                None => break,
+            };
+        }
+    }
+
+    // Cost to reach `to` from every origin, usize::MAX where it can't be reached
+    pub fn row(&mut self, to: Pair) -> &Grid<usize> {
+        self.clock += 1;
+        if !self.rows.contains_key(&to) {
+            self.evict();
+            let distances = if self.valid(to) {
+                self.grid.djikstra(to.extend(self.extent))
+            } else {
+                Grid::init(self.grid.effective_extent(self.extent), usize::MAX)
+            };
+            self.rows.insert(
+                to,
+                Row {
+                    distances,
+                    last_used: 0,
+                },
+            );
+        }
+        let row = self.rows.get_mut(&to).unwrap();
+        row.last_used = self.clock;
+        &row.distances
+    }
+
+    // None if either cell is blocked or out of bounds, or `to` can't be reached from `from`
+    pub fn distance(&mut self, from: Pair, to: Pair) -> Option<usize> {
+        if !self.valid(from) || !self.valid(to) {
+            return None;
+        }
+        let distance = self.row(to)[from];
+        (distance < usize::MAX).then_some(distance)
+    }
+
+    // out[Pair(i, j)] is the cost from origins[i] to destinations[j], usize::MAX if unreachable.
+    // One row per destination, so keep the destinations the shorter list.
+    pub fn pairwise(&mut self, origins: &[Pair], destinations: &[Pair]) -> Grid<usize> {
+        let mut out = Grid::init(
+            Pair(origins.len().max(1) - 1, destinations.len().max(1) - 1),
+            usize::MAX,
+        );
+        for (j, destination) in destinations.iter().enumerate() {
+            for (i, origin) in origins.iter().enumerate() {
+                if let Some(distance) = self.distance(*origin, *destination) {
+                    out[Pair(i, j)] = distance;
+                }
+            }
+        }
+        out
+    }
+
+    pub fn clear(&mut self) {
+        self.rows.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn distances_match_djikstra_under_eviction() {
+        let grid = testing::maze();
+        let extent = Pair(0, 0);
+        let size = grid.effective_size(extent);
+        // Room for two rows only
+        let mut oracle =
+            DistanceOracle::new(&grid, extent, 2 * size.0 * size.1 * size_of::<usize>());
+        assert_eq!(oracle.max_rows(), 2);
+        let cells = [Pair(0, 0), Pair(11, 8), Pair(4, 4), Pair(9, 2), Pair(2, 6)];
+        for to in cells {
+            let exact = grid.djikstra(to.extend(extent));
+            for from in cells {
+                let expected = (exact[from] < usize::MAX).then_some(exact[from]);
+                assert_eq!(oracle.distance(from, to), expected);
+            }
+            assert!(oracle.cached_rows() <= 2);
+        }
+        assert_eq!(oracle.distance(Pair(1, 1), Pair(0, 0)), None);
+
+        let pairwise = oracle.pairwise(&cells, &cells[..2]);
+        for (i, from) in cells.iter().enumerate() {
+            for (j, to) in cells[..2].iter().enumerate() {
+                assert_eq!(
+                    pairwise[Pair(i, j)],
+                    grid.djikstra(to.extend(extent))[*from]
+                );
+            }
+        }
+    }
+}
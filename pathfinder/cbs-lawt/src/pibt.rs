@@ -1,8 +1,9 @@
 use std::cmp::max;
 use std::rc::Rc;
-use std::usize;
+use std::sync::Arc;
 
 use crate::grid::Grid;
+use crate::heuristic::HeuristicCache;
 use crate::prelude::*;
 
 #[derive(PartialEq, Eq)]
@@ -20,13 +21,16 @@ struct PIBTState {
     next_queue: Vec<Rc<UnitState>>,
 }
 
+// TODO: take obstacles like AStar::set_obstacles() once the step loop below exists. Until
+// time advances there is no tick to check them against, so PIBT ignores them for now.
 #[derive(PartialEq, Eq)]
 pub struct PIBT {
     grid: Grid<CellInfo>,
     origins: Vec<Pair>,
     destinations: Vec<Pair>,
     unit_extent: Pair,
-    heuristics: Vec<Grid<usize>>,
+    movement_class: MovementClass,
+    heuristics: Vec<Arc<Grid<usize>>>,
     state: PIBTState,
 }
 
@@ -72,13 +76,14 @@ impl PIBT {
         Pair(min_x, min_y)
     }
 
-    fn find_heuristics(&mut self) {
+    fn find_heuristics(&mut self, cache: &HeuristicCache) {
         self.heuristics = Vec::with_capacity(self.destinations.len());
         for destination in &self.destinations {
-            let heuristic = self.grid.djikstra(Rect {
+            let destination = Rect {
                 origin: *destination,
                 extent: self.unit_extent,
-            });
+            };
+            let heuristic = cache.get(&self.grid, destination, self.movement_class);
             self.heuristics.push(heuristic);
         }
     }
@@ -169,6 +174,7 @@ impl PIBT {
             origins,
             destinations,
             unit_extent,
+            movement_class: MovementClass::default(),
             heuristics: Vec::new(),
             state: PIBTState {
                 this_queue: Vec::new(),
@@ -182,9 +188,25 @@ impl PIBT {
         origins: Vec<Pair>,
         destinations: Vec<Pair>,
         unit_extent: Pair,
+    ) -> PIBT {
+        PIBT::init_cached(
+            grid,
+            origins,
+            destinations,
+            unit_extent,
+            &HeuristicCache::new(),
+        )
+    }
+
+    pub fn init_cached(
+        grid: Grid<CellInfo>,
+        origins: Vec<Pair>,
+        destinations: Vec<Pair>,
+        unit_extent: Pair,
+        cache: &HeuristicCache,
     ) -> PIBT {
         let mut pibt = PIBT::new(grid, origins, destinations, unit_extent);
-        pibt.find_heuristics();
+        pibt.find_heuristics(cache);
         pibt.assign_destinations();
         pibt.improve_assignments();
         pibt
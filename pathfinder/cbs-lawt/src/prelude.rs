@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::ops;
-use std::rc::Rc;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Pair(pub usize, pub usize);
@@ -34,7 +33,11 @@ impl Pair {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+// Units of different classes may price terrain differently, so they cannot share distances.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MovementClass(pub usize);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct CellInfo {
     pub cost: usize,
     pub blocked: bool,
@@ -81,6 +84,18 @@ impl Rect {
         x <= cell.0 && cell.0 <= x + dx && y <= cell.1 && cell.1 <= y + dy
     }
 
+    // Steps between the nearest cells of the two rects, zero if they intersect
+    pub fn distance(self, rect_1: Rect) -> usize {
+        let gap = |low_0: usize, high_0: usize, low_1: usize, high_1: usize| {
+            low_0
+                .saturating_sub(high_1)
+                .max(low_1.saturating_sub(high_0))
+        };
+        let (max_0, max_1) = (self.max_coord(), rect_1.max_coord());
+        gap(self.origin.0, max_0.0, rect_1.origin.0, max_1.0)
+            + gap(self.origin.1, max_0.1, rect_1.origin.1, max_1.1)
+    }
+
     pub fn intersects(self, rect_1: Rect) -> bool {
         self.origin.0 <= rect_1.max_coord().0
             && rect_1.origin.0 <= self.max_coord().0
@@ -107,25 +122,26 @@ impl<T: Copy> HashMapExt<T> for HashMap<T, usize> {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 pub struct ScoredCell {
     // Cost including heuristic, what time do we think we will arrive?
     pub location: Rect,
     pub duration: Pair,
     pub cost: usize,
-    pub prev: Option<Rc<ScoredCell>>,
+    // Waypoints reached so far, counting this cell
+    pub leg: usize,
 }
 
 impl PartialEq for ScoredCell {
     fn eq(&self, other: &Self) -> bool {
-        self.location == other.location && self.duration == other.duration
+        self.location == other.location && self.duration == other.duration && self.leg == other.leg
     }
 }
 
 impl Eq for ScoredCell {}
 
-// Lowest cost has highest priority, then earliest departure, then earliest arrival, then we don't
-// really care, so we just do by cell then by prev.
+// Lowest cost has highest priority, then earliest departure, then earliest arrival, then most
+// waypoints reached, then we don't really care, so we just do by cell.
 impl Ord for ScoredCell {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         other
@@ -133,8 +149,8 @@ impl Ord for ScoredCell {
             .cmp(&self.cost)
             .then_with(|| other.duration.1.cmp(&self.duration.1))
             .then_with(|| other.duration.0.cmp(&self.duration.0))
+            .then_with(|| self.leg.cmp(&other.leg))
             .then_with(|| other.location.cmp(&self.location))
-            .then_with(|| other.prev.cmp(&self.prev))
     }
 }
 
@@ -156,6 +172,17 @@ impl Debug for ScoredCell {
 
 pub type Path = Vec<ScoredCell>;
 
+// Index of the cell where each waypoint was reached, in order
+pub fn waypoint_marks(path: &Path) -> Vec<usize> {
+    let mut out = Vec::new();
+    for (idx, scored_cell) in path.iter().enumerate() {
+        while out.len() < scored_cell.leg {
+            out.push(idx);
+        }
+    }
+    out
+}
+
 pub fn unfold_path(path: Path) -> Vec<Rect> {
     if path.is_empty() {
         return Vec::new();
@@ -177,50 +204,176 @@ pub struct ConflictInfo {
     pub duration: Pair,
 }
 
+// Two units overlap, or a follower strays further than `radius` from its leader at a turn
+// boundary. A stray lists the follower first, both durations are the boundary.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub struct Conflict(pub ConflictInfo, pub ConflictInfo);
+pub enum Conflict {
+    Collision(ConflictInfo, ConflictInfo),
+    Stray(ConflictInfo, ConflictInfo, usize),
+}
 
 impl Conflict {
     pub fn uids(self) -> (Pair, Pair) {
-        (self.0.uid, self.1.uid)
+        match self {
+            Conflict::Collision(info_0, info_1) | Conflict::Stray(info_0, info_1, _) => {
+                (info_0.uid, info_1.uid)
+            }
+        }
     }
 }
 
-// Constraint means that the unit may not collide with the region
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Constraint {
-    pub uid: Pair,
-    pub location: Rect,
-    pub duration: Pair,
+pub enum Constraint {
+    // The unit may not collide with the region
+    Avoid {
+        uid: Pair,
+        location: Rect,
+        duration: Pair,
+    },
+    // The unit must be within `radius` of the region at `time`
+    Near {
+        uid: Pair,
+        location: Rect,
+        radius: usize,
+        time: usize,
+    },
+    // The unit may be anywhere but exactly at `location` at `time`
+    Leave {
+        uid: Pair,
+        location: Rect,
+        time: usize,
+    },
+}
+
+impl Constraint {
+    pub fn uid(self) -> Pair {
+        match self {
+            Constraint::Avoid { uid, .. }
+            | Constraint::Near { uid, .. }
+            | Constraint::Leave { uid, .. } => uid,
+        }
+    }
 }
 
 impl Debug for Constraint {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "({}, {}) : ({}, {}) × ({}, {})",
-            self.uid.0,
-            self.uid.1,
-            self.location.origin.0,
-            self.location.origin.1,
-            self.duration.0,
-            self.duration.1
-        )
+        match self {
+            Constraint::Avoid {
+                uid,
+                location,
+                duration,
+            } => write!(
+                f,
+                "({}, {}) : ({}, {}) × ({}, {})",
+                uid.0, uid.1, location.origin.0, location.origin.1, duration.0, duration.1
+            ),
+            Constraint::Near {
+                uid,
+                location,
+                radius,
+                time,
+            } => write!(
+                f,
+                "({}, {}) : ({}, {}) ± {} × {}",
+                uid.0, uid.1, location.origin.0, location.origin.1, radius, time
+            ),
+            Constraint::Leave {
+                uid,
+                location,
+                time,
+            } => write!(
+                f,
+                "({}, {}) : ≠ ({}, {}) × {}",
+                uid.0, uid.1, location.origin.0, location.origin.1, time
+            ),
+        }
     }
 }
 
 impl Conflict {
     pub fn constraints(self) -> [Constraint; 2] {
-        let constraint_0 = Constraint {
-            uid: self.0.uid,
-            location: self.1.location,
-            duration: self.1.duration,
-        };
-        let constraint_1 = Constraint {
-            uid: self.1.uid,
-            location: self.0.location,
-            duration: self.0.duration,
-        };
-        [constraint_0, constraint_1]
+        match self {
+            Conflict::Collision(info_0, info_1) => [
+                Constraint::Avoid {
+                    uid: info_0.uid,
+                    location: info_1.location,
+                    duration: info_1.duration,
+                },
+                Constraint::Avoid {
+                    uid: info_1.uid,
+                    location: info_0.location,
+                    duration: info_0.duration,
+                },
+            ],
+            // Either the leader comes to where the follower is, or the follower is somewhere
+            // else. Every plan where the two move together falls in one of these.
+            Conflict::Stray(follower, leader, radius) => [
+                Constraint::Near {
+                    uid: leader.uid,
+                    location: follower.location,
+                    radius,
+                    time: follower.duration.0,
+                },
+                Constraint::Leave {
+                    uid: follower.uid,
+                    location: follower.location,
+                    time: follower.duration.0,
+                },
+            ],
+        }
+    }
+}
+
+// Keeps `follower` within `radius` of `leader` at the end of every turn of `turn` ticks, as a
+// commander's retinue. Distances are in steps between the nearest cells of the two units.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cohesion {
+    pub leader: Pair,
+    pub follower: Pair,
+    pub radius: usize,
+    pub turn: usize,
+}
+
+// Something outside the solve that occupies `location` over the ticks in `duration`, as an enemy
+// unit or a spell effect on a known schedule. A duration ending at usize::MAX never clears.
+// A* and CBS honour these, PIBT doesn't take them yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Obstacle {
+    pub location: Rect,
+    pub duration: Pair,
+}
+
+impl Obstacle {
+    // One obstacle per cell of a timed path, for something moving along it
+    pub fn along(path: &Path) -> Vec<Obstacle> {
+        path.iter()
+            .map(|scored_cell| Obstacle {
+                location: scored_cell.location,
+                duration: scored_cell.duration,
+            })
+            .collect()
+    }
+}
+
+// Which side a unit fights for. Allies may move through each other but never stop on one another,
+// anyone else blocks completely.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Faction(pub usize);
+
+impl Faction {
+    pub fn is_ally(self, other: Faction) -> bool {
+        self == other
     }
+
+    // Units without a faction have no allies
+    pub fn allied(a: Option<Faction>, b: Option<Faction>) -> bool {
+        a.zip(b).is_some_and(|(a, b)| a.is_ally(b))
+    }
+}
+
+// A unit standing on the map that the solve doesn't move
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Occupant {
+    pub location: Rect,
+    pub faction: Faction,
 }
@@ -0,0 +1,548 @@
+use crate::grid::Grid;
+use crate::prelude::*;
+use crate::zoc::{Zoc, ZocRule};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+
+// Every origin a unit can reach on a budget, with the cheapest way there. Built for movement
+// ranges, where the UI shows the reached cells and draws the path to whichever one is hovered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reachable {
+    start: Rect,
+    costs: HashMap<Pair, usize>,
+    // The origin we came from on a cheapest path, none for the start
+    prev: HashMap<Pair, Pair>,
+}
+
+impl Reachable {
+    pub fn start(&self) -> Rect {
+        self.start
+    }
+
+    pub fn len(&self) -> usize {
+        self.costs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.costs.is_empty()
+    }
+
+    pub fn contains(&self, origin: Pair) -> bool {
+        self.costs.contains_key(&origin)
+    }
+
+    pub fn cost(&self, origin: Pair) -> Option<usize> {
+        self.costs.get(&origin).copied()
+    }
+
+    pub fn predecessor(&self, origin: Pair) -> Option<Pair> {
+        self.prev.get(&origin).copied()
+    }
+
+    pub fn costs(&self) -> &HashMap<Pair, usize> {
+        &self.costs
+    }
+
+    // Every origin on a cheapest path from the start, the start first and `to` last
+    pub fn path_to(&self, to: Pair) -> Option<Vec<Pair>> {
+        if !self.contains(to) {
+            return None;
+        }
+        let mut path = vec![to];
+        let mut current = to;
+        while let Some(prev) = self.predecessor(current) {
+            path.push(prev);
+            current = prev;
+        }
+        path.reverse();
+        Some(path)
+    }
+}
+
+impl Grid<CellInfo> {
+    // Origins reachable from `from` for at most `budget`, never overlapping an `occupied` rect.
+    // The start is always reached, even if it overlaps one.
+    pub fn reachable(&self, from: Rect, budget: usize, occupied: &[Rect]) -> Reachable {
+        let overlaps = |location: Rect| occupied.iter().any(|other| other.intersects(location));
+        self.explore(from, budget, overlaps, |_| true, None)
+    }
+
+    // As reachable(), for a unit of `faction` among `occupants`. Enemies block, allies may be
+    // passed through but not stopped on. An occupant at `from` is taken to be the unit itself.
+    // With a ZOC rule, cells next to an enemy end the move or cost extra.
+    pub fn reachable_for(
+        &self,
+        from: Rect,
+        faction: Faction,
+        budget: usize,
+        occupants: &[Occupant],
+        zoc: Option<ZocRule>,
+    ) -> Reachable {
+        let others: Vec<&Occupant> = occupants
+            .iter()
+            .filter(|occupant| occupant.location != from)
+            .collect();
+        let overlaps = |location: Rect, allies: bool| {
+            others.iter().any(|occupant| {
+                occupant.faction.is_ally(faction) == allies
+                    && occupant.location.intersects(location)
+            })
+        };
+        let zoc = zoc.map(|rule| Zoc::new(self, Some(faction), occupants, rule));
+        self.explore(
+            from,
+            budget,
+            |location| overlaps(location, false),
+            |location| !overlaps(location, true),
+            zoc.as_ref(),
+        )
+    }
+
+    // Djikstra out to the budget. Origins passed through but not stoppable still lead elsewhere,
+    // so they keep their predecessor without counting as reached.
+    fn explore(
+        &self,
+        from: Rect,
+        budget: usize,
+        blocks: impl Fn(Rect) -> bool,
+        may_stop: impl Fn(Rect) -> bool,
+        zoc: Option<&Zoc>,
+    ) -> Reachable {
+        let mut out = Reachable {
+            start: from,
+            costs: HashMap::new(),
+            prev: HashMap::new(),
+        };
+        if !(self.in_bounds(from) && self.is_clear(from)) {
+            return out;
+        }
+        let mut settled = HashSet::new();
+        let mut open = BinaryHeap::from([Reverse((0, from.origin, None))]);
+        while let Some(Reverse((cost, origin, prev))) = open.pop() {
+            if !settled.insert(origin) {
+                continue;
+            }
+            let location = origin.extend(from.extent);
+            if origin == from.origin || may_stop(location) {
+                out.costs.insert(origin, cost);
+            }
+            if let Some(prev) = prev {
+                out.prev.insert(origin, prev);
+            }
+            if origin != from.origin && zoc.is_some_and(|zoc| zoc.stops(location)) {
+                continue;
+            }
+            for neighbor in self.neighbors(location) {
+                if settled.contains(&neighbor.origin) || blocks(neighbor) {
+                    continue;
+                }
+                let toll = zoc.map_or(0, |zoc| zoc.toll(neighbor));
+                let new_cost = cost + self.cost(neighbor) + toll;
+                if new_cost <= budget {
+                    open.push(Reverse((new_cost, neighbor.origin, Some(origin))));
+                }
+            }
+        }
+        out
+    }
+}
+
+// Where a unit has to end up to count as reaching a target
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Approach {
+    // Overlapping the target
+    Onto,
+    // Sharing an edge with the target without overlapping it, as when attacking a unit
+    Adjacent,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mover {
+    pub location: Rect,
+    pub budget: usize,
+    pub faction: Faction,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Arrival {
+    // Origin of the unit, as everywhere else
+    pub uid: Pair,
+    pub cost: usize,
+    // Every origin on the way, the unit's origin first
+    pub path: Vec<Pair>,
+}
+
+impl Grid<CellInfo> {
+    // Clear origins for a unit with `extent` that count as reaching `target`
+    fn approaches(&self, target: Rect, approach: Approach, extent: Pair) -> Vec<Rect> {
+        // One more on each side, for the ones adjacent to the target
+        let low = Pair(
+            target.origin.0.saturating_sub(extent.0 + 1),
+            target.origin.1.saturating_sub(extent.1 + 1),
+        );
+        let high = target.max_coord() + Pair(1, 1);
+        let mut out = Vec::new();
+        for x in low.0..=high.0 {
+            for y in low.1..=high.1 {
+                let location = Pair(x, y).extend(extent);
+                let reaches = match approach {
+                    Approach::Onto => location.intersects(target),
+                    Approach::Adjacent => {
+                        !location.intersects(target)
+                            && [
+                                Pair(1, 0),
+                                Pair(0, 1),
+                                Pair(usize::MAX, 0),
+                                Pair(0, usize::MAX),
+                            ]
+                            .iter()
+                            .any(|step| (location + *step).intersects(target))
+                    }
+                };
+                if reaches && self.in_bounds(location) && self.is_clear(location) {
+                    out.push(location);
+                }
+            }
+        }
+        out
+    }
+
+    // Follows a distance field to one of the `targets` it was built from, only along steps a
+    // cheapest path can take. Zero-cost cells leave ties and zeros short of the targets, so it
+    // goes breadth first and remembers where it came from. Only the last step may enter a zone of
+    // control that stops the unit.
+    fn descend(
+        &self,
+        field: &Grid<usize>,
+        targets: &[Rect],
+        from: Rect,
+        zoc: Option<&Zoc>,
+    ) -> Vec<Pair> {
+        let mut prev = HashMap::from([(from.origin, from.origin)]);
+        let mut open = VecDeque::from([from]);
+        while let Some(current) = open.pop_front() {
+            if targets.contains(&current) {
+                let mut path = vec![current.origin];
+                let mut origin = current.origin;
+                while origin != from.origin {
+                    origin = prev[&origin];
+                    path.push(origin);
+                }
+                path.reverse();
+                return path;
+            }
+            for next in self.neighbors(current) {
+                if prev.contains_key(&next.origin)
+                    || !targets.contains(&next) && zoc.is_some_and(|zoc| zoc.stops(next))
+                {
+                    continue;
+                }
+                let step = self.cost(next) + zoc.map_or(0, |zoc| zoc.toll(next));
+                if field[next.origin].saturating_add(step) == field[current.origin] {
+                    prev.insert(next.origin, current.origin);
+                    open.push_back(next);
+                }
+            }
+        }
+        panic!("A finite distance has a next step!")
+    }
+
+    // The units that can reach `target` within their budget, with one reverse search per unit size
+    // and faction instead of one search per unit. Units that can't are left out. Enemies among
+    // the `occupants` block, allies may be passed through but not stopped on, and with a ZOC rule
+    // the cells next to enemies end the move or cost extra.
+    pub fn reachable_by(
+        &self,
+        target: Rect,
+        approach: Approach,
+        units: &[Mover],
+        occupants: &[Occupant],
+        zoc: Option<ZocRule>,
+    ) -> Vec<Arrival> {
+        type Field = Option<(Grid<usize>, Vec<Rect>, Option<Zoc>)>;
+        let mut fields: HashMap<(Pair, Faction), Field> = HashMap::new();
+        let mut out = Vec::new();
+        for unit in units {
+            let extent = unit.location.extent;
+            if !(self.in_bounds(unit.location) && self.is_clear(unit.location)) {
+                continue;
+            }
+            // Already there, whoever else is around
+            if self
+                .approaches(target, approach, extent)
+                .contains(&unit.location)
+            {
+                out.push(Arrival {
+                    uid: unit.location.origin,
+                    cost: 0,
+                    path: vec![unit.location.origin],
+                });
+                continue;
+            }
+            let field = fields.entry((extent, unit.faction)).or_insert_with(|| {
+                let occupied: Vec<Rect> =
+                    occupants.iter().map(|occupant| occupant.location).collect();
+                let targets: Vec<Rect> = self
+                    .approaches(target, approach, extent)
+                    .into_iter()
+                    .filter(|location| !occupied.iter().any(|other| other.intersects(*location)))
+                    .collect();
+                let enemies: Vec<Rect> = occupants
+                    .iter()
+                    .filter(|occupant| !occupant.faction.is_ally(unit.faction))
+                    .map(|occupant| occupant.location)
+                    .collect();
+                let zoc = zoc.map(|rule| Zoc::new(self, Some(unit.faction), occupants, rule));
+                (!targets.is_empty()).then(|| {
+                    let field = self.djikstra_to_any_avoiding(&targets, &enemies, zoc.as_ref());
+                    (field, targets, zoc)
+                })
+            });
+            let Some((field, targets, zoc)) = field else {
+                continue;
+            };
+            let cost = field[unit.location.origin];
+            if cost < usize::MAX && cost <= unit.budget {
+                out.push(Arrival {
+                    uid: unit.location.origin,
+                    cost,
+                    path: self.descend(field, targets, unit.location, zoc.as_ref()),
+                });
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    // Everything Djikstra puts within the budget is reached at that cost, by a path that pays it
+    fn check_range(grid: &Grid<CellInfo>, range: &Reachable, budget: usize) {
+        let from = range.start();
+        let exact = grid.djikstra_from(from);
+        for origin in testing::clear_origins(grid, from.extent) {
+            let cost = range.cost(origin);
+            if exact[origin] <= budget {
+                assert_eq!(cost, Some(exact[origin]), "{:?} from {:?}", origin, from);
+                let locations: Vec<Rect> = range
+                    .path_to(origin)
+                    .unwrap()
+                    .iter()
+                    .map(|origin| origin.extend(from.extent))
+                    .collect();
+                assert_eq!(locations[0], from);
+                assert_eq!(testing::walk_cost(grid, &locations), exact[origin]);
+            } else {
+                assert_eq!(cost, None, "{:?} from {:?}", origin, from);
+            }
+        }
+    }
+
+    #[test]
+    fn ranges_match_djikstra() {
+        let grid = testing::maze();
+        for budget in [0, 3, 8, 100] {
+            check_range(
+                &grid,
+                &grid.reachable(Pair(0, 0).extend(Pair(0, 0)), budget, &[]),
+                budget,
+            );
+            check_range(
+                &grid,
+                &grid.reachable(Pair(7, 6).extend(Pair(0, 0)), budget, &[]),
+                budget,
+            );
+        }
+        let grid = testing::random(37, Pair(15, 11), 0.15, 3);
+        for from in testing::clear_origins(&grid, Pair(1, 1))
+            .into_iter()
+            .step_by(9)
+        {
+            check_range(&grid, &grid.reachable(from.extend(Pair(1, 1)), 12, &[]), 12);
+        }
+    }
+
+    #[test]
+    fn occupied_cells_block_like_walls() {
+        let mut grid = testing::maze();
+        let occupied = [Pair(2, 3).extend(Pair(0, 0)), Pair(9, 0).extend(Pair(0, 1))];
+        let from = Pair(0, 0).extend(Pair(0, 0));
+        let range = grid.reachable(from, 30, &occupied);
+        for rect in occupied {
+            grid.set_blocked(rect, true);
+        }
+        check_range(&grid, &range, 30);
+    }
+
+    fn check_arrivals(grid: &Grid<CellInfo>, target: Rect, approach: Approach, units: &[Mover]) {
+        let arrivals = grid.reachable_by(target, approach, units, &[], None);
+        check_found(grid, target, approach, units, &arrivals);
+    }
+
+    // The units within budget of Djikstra to any approach on `grid`, each with a path that pays
+    // its cost
+    fn check_found(
+        grid: &Grid<CellInfo>,
+        target: Rect,
+        approach: Approach,
+        units: &[Mover],
+        arrivals: &[Arrival],
+    ) {
+        for unit in units {
+            let extent = unit.location.extent;
+            let exact = grid.djikstra_to_any(&grid.approaches(target, approach, extent))
+                [unit.location.origin];
+            let arrival = arrivals
+                .iter()
+                .find(|arrival| arrival.uid == unit.location.origin);
+            if exact > unit.budget {
+                assert_eq!(arrival, None);
+                continue;
+            }
+            let arrival = arrival.expect("Within budget!");
+            assert_eq!(arrival.cost, exact, "{:?}", unit);
+            let locations: Vec<Rect> = arrival
+                .path
+                .iter()
+                .map(|origin| origin.extend(extent))
+                .collect();
+            assert_eq!(testing::walk_cost(grid, &locations), exact);
+            let last = locations[locations.len() - 1];
+            assert!(grid.approaches(target, approach, extent).contains(&last));
+        }
+    }
+
+    #[test]
+    fn arrivals_match_djikstra() {
+        let grid = testing::maze();
+        let units: Vec<Mover> = testing::clear_origins(&grid, Pair(0, 0))
+            .into_iter()
+            .step_by(3)
+            .enumerate()
+            .map(|(i, origin)| Mover {
+                location: origin.extend(Pair(0, 0)),
+                budget: 5 + i % 20,
+                faction: Faction(0),
+            })
+            .collect();
+        for approach in [Approach::Onto, Approach::Adjacent] {
+            check_arrivals(&grid, Pair(5, 4).extend(Pair(1, 0)), approach, &units);
+            check_arrivals(&grid, Pair(0, 8).extend(Pair(0, 0)), approach, &units);
+        }
+    }
+
+    #[test]
+    fn zero_cost_cells_lead_down_the_field() {
+        // Plateaus of free cells tie every step across them
+        let grid = testing::parse(&["1000000", "0000000", "0000#00", "1000#01"]);
+        let units: Vec<Mover> = testing::clear_origins(&grid, Pair(0, 0))
+            .into_iter()
+            .map(|origin| Mover {
+                location: origin.extend(Pair(0, 0)),
+                budget: 10,
+                faction: Faction(0),
+            })
+            .collect();
+        check_arrivals(&grid, Pair(6, 3).extend(Pair(0, 0)), Approach::Onto, &units);
+        check_arrivals(
+            &grid,
+            Pair(0, 0).extend(Pair(0, 0)),
+            Approach::Adjacent,
+            &units,
+        );
+    }
+
+    fn corridor() -> (Grid<CellInfo>, Vec<Occupant>) {
+        let grid = testing::parse(&["............", "444444444444"]);
+        let enemy = Occupant {
+            location: Pair(6, 1).extend(Pair(0, 0)),
+            faction: Faction(1),
+        };
+        (grid, vec![enemy])
+    }
+
+    // The corridor with the enemy walled in and its zone of control costing 3 more
+    fn tolled() -> Grid<CellInfo> {
+        testing::parse(&["......4.....", "444447#74444"])
+    }
+
+    #[test]
+    fn zones_of_control_toll_like_dearer_cells() {
+        let (grid, occupants) = corridor();
+        let toll = Some(ZocRule::Toll(3));
+        let from = Pair(0, 0).extend(Pair(0, 0));
+        for budget in [6, 9, 14, 30] {
+            let range = grid.reachable_for(from, Faction(0), budget, &occupants, toll);
+            check_range(&tolled(), &range, budget);
+        }
+        assert_eq!(
+            grid.reachable_for(from, Faction(0), 30, &occupants, toll)
+                .cost(Pair(7, 0)),
+            Some(10)
+        );
+        let units: Vec<Mover> = testing::clear_origins(&tolled(), Pair(0, 0))
+            .into_iter()
+            .map(|origin| Mover {
+                location: origin.extend(Pair(0, 0)),
+                budget: 12,
+                faction: Faction(0),
+            })
+            .collect();
+        for (target, approach) in [
+            (Pair(11, 0).extend(Pair(0, 0)), Approach::Onto),
+            (Pair(0, 1).extend(Pair(0, 0)), Approach::Adjacent),
+        ] {
+            let arrivals = grid.reachable_by(target, approach, &units, &occupants, toll);
+            check_found(&tolled(), target, approach, &units, &arrivals);
+        }
+    }
+
+    #[test]
+    fn zones_of_control_end_the_move() {
+        let (grid, occupants) = corridor();
+        let stop = Some(ZocRule::Stop { turn: 5 });
+        let from = Pair(0, 0).extend(Pair(0, 0));
+        let range = grid.reachable_for(from, Faction(0), 30, &occupants, stop);
+        // Djikstra with the zone walled off, then one step into it
+        let mut walled = tolled();
+        for zone in [Pair(6, 0), Pair(5, 1), Pair(7, 1)] {
+            walled.set_blocked(zone.extend(Pair(0, 0)), true);
+        }
+        let exact = walled.djikstra_from(from);
+        for origin in testing::clear_origins(&tolled(), Pair(0, 0)) {
+            let expected = match origin {
+                Pair(6, 0) => Some(6),
+                Pair(5, 1) => Some(9),
+                Pair(x, _) if x > 6 => None,
+                _ => Some(exact[origin]),
+            };
+            assert_eq!(range.cost(origin), expected, "{:?}", origin);
+        }
+        // Starting in the zone, leaving it is free
+        let units = [Pair(0, 0), Pair(6, 0), Pair(8, 0)].map(|origin| Mover {
+            location: origin.extend(Pair(0, 0)),
+            budget: 30,
+            faction: Faction(0),
+        });
+        let costs = |target: Pair| {
+            let arrivals = grid.reachable_by(
+                target.extend(Pair(0, 0)),
+                Approach::Onto,
+                &units,
+                &occupants,
+                stop,
+            );
+            units.map(|unit| {
+                arrivals
+                    .iter()
+                    .find(|arrival| arrival.uid == unit.location.origin)
+                    .map(|arrival| arrival.cost)
+            })
+        };
+        assert_eq!(costs(Pair(11, 0)), [None, Some(5), Some(3)]);
+        assert_eq!(costs(Pair(6, 0)), [Some(6), Some(0), Some(2)]);
+    }
+}
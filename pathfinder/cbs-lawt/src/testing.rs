@@ -0,0 +1,86 @@
+// Small fixed and seeded grids for the unit tests
+use crate::grid::Grid;
+use crate::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// '#' is blocked, a digit is the cost of the cell, anything else costs 1. Rows are y.
+pub fn parse(rows: &[&str]) -> Grid<CellInfo> {
+    let extent = Pair(rows[0].len() - 1, rows.len() - 1);
+    let mut grid = Grid::init(
+        extent,
+        CellInfo {
+            cost: 1,
+            blocked: false,
+        },
+    );
+    for (y, row) in rows.iter().enumerate() {
+        for (x, tile) in row.chars().enumerate() {
+            match tile {
+                '#' => grid[Pair(x, y)].blocked = true,
+                digit if digit.is_ascii_digit() => {
+                    grid[Pair(x, y)].cost = digit.to_digit(10).unwrap() as usize
+                }
+                _ => {}
+            }
+        }
+    }
+    grid
+}
+
+// Walls with gaps and a few expensive patches
+pub fn maze() -> Grid<CellInfo> {
+    parse(&[
+        "............",
+        ".####.#####.",
+        ".#..3.....#.",
+        ".#.####.#.#.",
+        "...#..#.#...",
+        "##.#.22.###.",
+        "...#..#.....",
+        ".#####.####.",
+        "......3.....",
+    ])
+}
+
+pub fn open(extent: Pair) -> Grid<CellInfo> {
+    Grid::init(
+        extent,
+        CellInfo {
+            cost: 1,
+            blocked: false,
+        },
+    )
+}
+
+// Uniform cost with `density` of the cells blocked
+pub fn random(seed: u64, extent: Pair, density: f64, max_cost: usize) -> Grid<CellInfo> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut grid = open(extent);
+    for (_, cell) in grid.indexed_iter_mut() {
+        cell.blocked = rng.gen_bool(density);
+        cell.cost = rng.gen_range(1..=max_cost);
+    }
+    grid
+}
+
+// Every clear origin for a unit of `extent`
+pub fn clear_origins(grid: &Grid<CellInfo>, extent: Pair) -> Vec<Pair> {
+    grid.clear_origins(extent).collect()
+}
+
+// What a path pays for terrain, checking that it only takes clear single steps
+pub fn walk_cost(grid: &Grid<CellInfo>, path: &[Rect]) -> usize {
+    for pair in path.windows(2) {
+        assert!(
+            grid.neighbors(pair[0]).contains(&pair[1]),
+            "{:?} to {:?} is not a step",
+            pair[0],
+            pair[1]
+        );
+    }
+    path.iter()
+        .skip(1)
+        .map(|location| grid.cost(*location))
+        .sum()
+}
@@ -0,0 +1,154 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::astar::AStar;
+use crate::cbs::solve_mapf_cancellable;
+use crate::prelude::Path;
+
+// Solvers are handed to worker threads, so they must stay thread safe.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<AStar>();
+    assert_send_sync::<Path>();
+};
+
+// Shared flag that a solver polls to find out it should give up early.
+#[derive(Clone, Default, Debug)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// A solver running on its own thread. The result is None if the solver was cancelled (or gave up).
+pub struct SolveHandle<T> {
+    cancel: CancelToken,
+    thread: JoinHandle<Option<T>>,
+}
+
+impl<T: Send + 'static> SolveHandle<T> {
+    pub fn spawn<F>(solver: F) -> SolveHandle<T>
+    where
+        F: FnOnce(&CancelToken) -> Option<T> + Send + 'static,
+    {
+        let cancel = CancelToken::new();
+        let token = cancel.clone();
+        let thread = thread::spawn(move || solver(&token));
+        SolveHandle { cancel, thread }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    // Asks the solver to stop. It will do so the next time it checks the token.
+    pub fn cancel(&self) {
+        self.cancel.cancel()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    // Blocks until the solver is done. Panics on the worker are propagated.
+    pub fn join(self) -> Option<T> {
+        match self.thread.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+
+    // Non-blocking join, hands the handle back if the solver is still running.
+    pub fn try_join(self) -> Result<Option<T>, SolveHandle<T>> {
+        if self.is_finished() {
+            Ok(self.join())
+        } else {
+            Err(self)
+        }
+    }
+
+    pub fn cancel_and_join(self) -> Option<T> {
+        self.cancel();
+        self.join()
+    }
+}
+
+// The caller keeps its own reference to `mapf`, e.g. to keep drawing the grid in the meantime.
+pub fn spawn_mapf(mapf: Arc<AStar>) -> SolveHandle<Vec<Path>> {
+    SolveHandle::spawn(move |cancel| solve_mapf_cancellable(&mapf, cancel))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cbs::solve_mapf;
+    use crate::prelude::*;
+    use crate::testing;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn spawned_solves_match_solving_in_place() {
+        // Units crossing each other in the open
+        let mapf = Arc::new(AStar::init(
+            vec![Pair(0, 3), Pair(3, 0), Pair(0, 4)],
+            vec![Pair(7, 3), Pair(3, 7), Pair(7, 4)],
+            Pair(0, 0),
+            testing::open(Pair(7, 7)),
+        ));
+        let handle = spawn_mapf(Arc::clone(&mapf));
+        assert_eq!(handle.join(), Some(solve_mapf(&mapf)));
+    }
+
+    #[test]
+    fn cancelled_solves_give_up_quickly() {
+        // A follower that must share the leader's cell never settles, so the search runs on
+        let mut mapf = AStar::init(
+            vec![Pair(0, 0), Pair(0, 1)],
+            vec![Pair(9, 0), Pair(9, 1)],
+            Pair(0, 0),
+            testing::parse(&["..........", ".........."]),
+        );
+        mapf.cohesion = vec![Cohesion {
+            leader: Pair(0, 0),
+            follower: Pair(0, 1),
+            radius: 0,
+            turn: 1,
+        }];
+        let handle = spawn_mapf(Arc::new(mapf));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!handle.is_finished());
+        let cancelled = Instant::now();
+        assert_eq!(handle.cancel_and_join(), None);
+        assert!(cancelled.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn handles_hand_back_unfinished_solves() {
+        let handle = SolveHandle::spawn(|cancel| {
+            while !cancel.is_cancelled() {
+                thread::yield_now();
+            }
+            Some(1)
+        });
+        let handle = handle.try_join().err().unwrap();
+        handle.cancel();
+        assert!(handle.is_cancelled());
+        assert_eq!(handle.join(), Some(1));
+        let handle = SolveHandle::spawn(|_| Some(2));
+        while !handle.is_finished() {
+            thread::yield_now();
+        }
+        assert_eq!(handle.try_join().ok(), Some(Some(2)));
+    }
+}
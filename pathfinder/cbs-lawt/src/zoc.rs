@@ -0,0 +1,83 @@
+use crate::grid::Grid;
+use crate::prelude::*;
+
+// What entering an enemy's zone of control does to a unit. Leaving one it starts in is free.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ZocRule {
+    // Ends the unit's move. Planning over turns of `turn` ticks, it waits out the rest of the turn.
+    Stop { turn: usize },
+    // Costs this many ticks on top of the terrain
+    Toll(usize),
+}
+
+// The cells sharing an edge with an enemy of one faction
+#[derive(PartialEq, Eq)]
+pub struct Zoc {
+    pub rule: ZocRule,
+    layer: Grid<bool>,
+}
+
+impl Zoc {
+    // From the footprints of the occupants not allied with `faction`. Enemies moving in the same
+    // solve don't project a zone.
+    pub fn new(
+        grid: &Grid<CellInfo>,
+        faction: Option<Faction>,
+        occupants: &[Occupant],
+        rule: ZocRule,
+    ) -> Zoc {
+        let extent = grid.extent();
+        let mut layer = Grid::init(extent, false);
+        for occupant in occupants {
+            if Faction::allied(Some(occupant.faction), faction) {
+                continue;
+            }
+            // Marks the footprint too, which enemies keep anyone from entering anyway
+            for Pair(x, y) in occupant.location.cells() {
+                if x > 0 {
+                    layer[Pair(x - 1, y)] = true;
+                }
+                if y > 0 {
+                    layer[Pair(x, y - 1)] = true;
+                }
+                if x < extent.0 {
+                    layer[Pair(x + 1, y)] = true;
+                }
+                if y < extent.1 {
+                    layer[Pair(x, y + 1)] = true;
+                }
+            }
+        }
+        Zoc { rule, layer }
+    }
+
+    pub fn contains(&self, location: Rect) -> bool {
+        location.cells().into_iter().any(|cell| self.layer[cell])
+    }
+
+    // Does entering `location` end the move?
+    pub fn stops(&self, location: Rect) -> bool {
+        matches!(self.rule, ZocRule::Stop { .. }) && self.contains(location)
+    }
+
+    // Extra ticks for entering `location`
+    pub fn toll(&self, location: Rect) -> usize {
+        match self.rule {
+            ZocRule::Toll(toll) if self.contains(location) => toll,
+            _ => 0,
+        }
+    }
+
+    // Last tick a unit done entering `location` at `time` has to stay. Turn k runs from tick
+    // k * turn to k * turn + turn - 1, so a unit stopped on a turn's first tick sits out all of
+    // it, and one stopped on its last tick moves on with the next turn.
+    pub fn hold(&self, location: Rect, time: usize) -> usize {
+        match self.rule {
+            ZocRule::Stop { turn } if self.contains(location) => {
+                let turn = turn.max(1);
+                (time / turn + 1) * turn - 1
+            }
+            _ => time,
+        }
+    }
+}